@@ -0,0 +1,297 @@
+use crate::api::has_bearer;
+use crate::api::json_response;
+use crate::api::read_json;
+use crate::composite_discovery::Contributions;
+use crate::constraints::Constraints;
+use crate::constraints::Violation;
+use crate::db::DB;
+use crate::health_check::ReportCounts;
+use crate::health_check::ServerStates;
+use crate::health_check::Usage;
+use crate::planner::plan_membership;
+use crate::planner::plan_rebalance;
+use crate::planner::ApplyError;
+use crate::planner::Plan;
+use crate::rebalance::RebalanceStrategy;
+use crate::selection::SliceSelection;
+use crate::slice_assignments::Cordon;
+use crate::slice_assignments::SliceAssignments;
+use crate::traffic::UsageSource;
+use async_trait::async_trait;
+use http::Method;
+use http::Response;
+use http::StatusCode;
+use pingora_core::apps::http_app::ServeHttp;
+use pingora_core::protocols::http::ServerSession;
+use pingora_load_balancing::LoadBalancer;
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+#[derive(Debug, PartialEq, serde::Serialize)]
+struct ServerStatus {
+    address: SocketAddr,
+    cordon: Option<Cordon>,
+    slices: usize,
+    /// As of the last health check, if there has been one.
+    healthy: Option<bool>,
+    /// Total load the server last reported.
+    load: Option<u32>,
+    last_check_secs_ago: Option<u64>,
+    usage_age_secs: Option<u64>,
+    /// Usage reports dropped since the server was discovered.
+    reports: Option<ReportCounts>,
+}
+
+fn server_status(
+    assignments: &SliceAssignments,
+    states: &ServerStates,
+    address: SocketAddr,
+) -> ServerStatus {
+    let state = states
+        .get(&address)
+        .map(|status| status.inner.read().unwrap().clone());
+    ServerStatus {
+        address,
+        cordon: assignments.cordons.get(&address).copied(),
+        slices: assignments.slice_count(&address),
+        healthy: state.as_ref().map(|s| s.is_healthy),
+        load: state
+            .as_ref()
+            .and_then(|s| s.usage.as_ref())
+            .map(|usage| usage.slices.values().map(|s| s.load).sum()),
+        last_check_secs_ago: state.as_ref().map(|s| s.last_check.elapsed().as_secs()),
+        usage_age_secs: state
+            .as_ref()
+            .and_then(|s| s.usage_age())
+            .map(|age| age.as_secs()),
+        reports: state.map(|s| s.reports),
+    }
+}
+
+#[derive(serde::Serialize)]
+struct ConstraintsStatus {
+    constraints: Constraints,
+    violations: Vec<Violation>,
+}
+
+/// Body of a `POST /plan` request.
+#[derive(serde::Deserialize)]
+struct PlanRequest {
+    /// Preview moving to this set of servers. Without it the plan is a
+    /// rebalance.
+    #[serde(default)]
+    servers: Option<BTreeSet<SocketAddr>>,
+    /// Usage to plan with, defaults to what the servers last reported.
+    #[serde(default)]
+    usage: Option<HashMap<SocketAddr, Usage>>,
+}
+
+#[derive(serde::Serialize)]
+struct PlanResponse {
+    #[serde(flatten)]
+    plan: Plan,
+    summary: String,
+}
+
+/// HTTP API for operators:
+///
+/// - `GET /servers` lists every server with its cordon state, slice count,
+///   health and reported load.
+/// - `GET /servers/{address}` shows one server, poll this after draining until
+///   it owns zero slices.
+/// - `POST /servers/{address}/cordon` stops new slices going to the server.
+/// - `POST /servers/{address}/drain` also moves its slices away gradually.
+/// - `POST /servers/{address}/uncordon` puts it back into rotation.
+/// - `GET /moves` lists recent slice moves and moves deferred by the churn
+///   budget.
+/// - `GET /traffic` shows the traffic to each slice seen by this load
+///   balancer.
+/// - `GET /constraints` shows the placement constraints and any that can't
+///   currently be met.
+/// - `PUT /constraints` replaces the placement constraints.
+/// - `POST /plan` previews the slice moves for a proposed set of servers, or
+///   a rebalance, without changing anything.
+/// - `POST /plan/apply` with a plan from `/plan` makes those moves, a 409 means
+///   the assignments changed in the meantime and the plan needs redoing.
+/// - `GET /discovery` shows which discovery source each server came from,
+///   given `contributions`.
+///
+/// Given a `token`, requests that change anything need it as a bearer token.
+pub struct AdminApi {
+    db: DB,
+    upstreams: Arc<LoadBalancer<SliceSelection>>,
+    strategy: Box<dyn RebalanceStrategy>,
+    usage: UsageSource,
+    pub token: Option<String>,
+    pub contributions: Option<Contributions>,
+}
+
+impl AdminApi {
+    pub fn new(
+        db: DB,
+        upstreams: Arc<LoadBalancer<SliceSelection>>,
+        strategy: Box<dyn RebalanceStrategy>,
+        usage: UsageSource,
+    ) -> Self {
+        Self {
+            db,
+            upstreams,
+            strategy,
+            usage,
+            token: None,
+            contributions: None,
+        }
+    }
+
+    async fn plan(&self, session: &mut ServerSession) -> (StatusCode, String) {
+        let request = match read_json::<PlanRequest>(session).await {
+            Ok(request) => request,
+            Err(e) => return (StatusCode::BAD_REQUEST, e),
+        };
+        let (assignments, version) = match self.db.versioned_assignments().await {
+            Ok(assignments) => assignments,
+            Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+        };
+        let usage = request
+            .usage
+            .unwrap_or_else(|| self.usage.usage(&self.upstreams, &assignments));
+        let plan = match request.servers {
+            Some(servers) => plan_membership(&assignments, version, &servers, &usage),
+            None => plan_rebalance(&assignments, version, &usage, self.strategy.as_ref()),
+        };
+        let summary = plan.to_string();
+        (
+            StatusCode::OK,
+            serde_json::to_string(&PlanResponse { plan, summary }).unwrap(),
+        )
+    }
+
+    async fn apply(&self, session: &mut ServerSession) -> (StatusCode, String) {
+        let plan = match read_json::<Plan>(session).await {
+            Ok(plan) => plan,
+            Err(e) => return (StatusCode::BAD_REQUEST, e),
+        };
+        match self.db.apply_plan(&plan).await {
+            Ok(_) => {
+                println!("applied plan: {}", plan);
+                (StatusCode::OK, String::new())
+            }
+            Err(e @ ApplyError::VersionChanged { .. }) => (StatusCode::CONFLICT, e.to_string()),
+            Err(e @ ApplyError::InvalidMove(_)) => (StatusCode::BAD_REQUEST, e.to_string()),
+            Err(e @ ApplyError::Db(_)) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+        }
+    }
+
+    async fn handle(&self, session: &mut ServerSession) -> (StatusCode, String) {
+        let method = session.req_header().method.clone();
+        let path = session.req_header().uri.path().to_string();
+        let segments: Vec<_> = path.trim_matches('/').split('/').collect();
+        let read_only = method == Method::GET || (method == Method::POST && segments == ["plan"]);
+        if let Some(token) = self.token.as_ref().filter(|_| !read_only) {
+            if !has_bearer(session, token) {
+                return (StatusCode::UNAUTHORIZED, "unauthorized".to_string());
+            }
+        }
+        match (&method, segments.as_slice()) {
+            (&Method::POST, ["plan"]) => return self.plan(session).await,
+            (&Method::POST, ["plan", "apply"]) => return self.apply(session).await,
+            (&Method::GET, ["discovery"]) => {
+                return match &self.contributions {
+                    Some(contributions) => (
+                        StatusCode::OK,
+                        serde_json::to_string(&*contributions.read().unwrap()).unwrap(),
+                    ),
+                    None => (
+                        StatusCode::NOT_FOUND,
+                        "not using composite discovery".to_string(),
+                    ),
+                }
+            }
+            _ => {}
+        }
+
+        let assignments = match self.db.current_assignments().await {
+            Ok(assignments) => assignments,
+            Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+        };
+        match (method, segments.as_slice()) {
+            (Method::GET, ["servers"]) => {
+                let servers: Vec<_> = assignments
+                    .servers
+                    .iter()
+                    .map(|&s| server_status(&assignments, &self.db.states, s))
+                    .collect();
+                (StatusCode::OK, serde_json::to_string(&servers).unwrap())
+            }
+            (Method::GET, ["traffic"]) => (
+                StatusCode::OK,
+                serde_json::to_string(&self.usage.traffic.stats()).unwrap(),
+            ),
+            (Method::GET, ["moves"]) => (
+                StatusCode::OK,
+                serde_json::to_string(&assignments.churn).unwrap(),
+            ),
+            (Method::GET, ["constraints"]) => {
+                let status = ConstraintsStatus {
+                    violations: assignments.violations(),
+                    constraints: assignments.constraints,
+                };
+                (StatusCode::OK, serde_json::to_string(&status).unwrap())
+            }
+            (Method::PUT, ["constraints"]) => {
+                let constraints = match read_json::<Constraints>(session).await {
+                    Ok(constraints) => constraints,
+                    Err(e) => return (StatusCode::BAD_REQUEST, e),
+                };
+                match self.db.set_constraints(constraints.clone()).await {
+                    Ok(violations) => {
+                        println!("set constraints to {:?}", constraints);
+                        let status = ConstraintsStatus {
+                            constraints,
+                            violations,
+                        };
+                        (StatusCode::OK, serde_json::to_string(&status).unwrap())
+                    }
+                    Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+                }
+            }
+            (method, ["servers", address, action @ ..]) => {
+                let Ok(address) = address.parse::<SocketAddr>() else {
+                    return (StatusCode::BAD_REQUEST, "invalid address".to_string());
+                };
+                if !assignments.servers.contains(&address) {
+                    return (StatusCode::NOT_FOUND, "unknown server".to_string());
+                }
+                let cordon = match (method, action) {
+                    (Method::GET, []) => {
+                        let status = server_status(&assignments, &self.db.states, address);
+                        return (StatusCode::OK, serde_json::to_string(&status).unwrap());
+                    }
+                    (Method::POST, ["cordon"]) => Some(Cordon::Cordoned),
+                    (Method::POST, ["drain"]) => Some(Cordon::Draining),
+                    (Method::POST, ["uncordon"]) => None,
+                    _ => return (StatusCode::NOT_FOUND, "not found".to_string()),
+                };
+                match self.db.set_cordon(address, cordon).await {
+                    Ok(assignments) => {
+                        println!("set cordon of {} to {:?}", address, cordon);
+                        let status = server_status(&assignments, &self.db.states, address);
+                        (StatusCode::OK, serde_json::to_string(&status).unwrap())
+                    }
+                    Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+                }
+            }
+            _ => (StatusCode::NOT_FOUND, "not found".to_string()),
+        }
+    }
+}
+
+#[async_trait]
+impl ServeHttp for AdminApi {
+    async fn response(&self, session: &mut ServerSession) -> Response<Vec<u8>> {
+        let (status, body) = self.handle(session).await;
+        json_response(status, body)
+    }
+}
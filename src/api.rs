@@ -0,0 +1,78 @@
+use http::Response;
+use http::StatusCode;
+use pingora_core::protocols::http::ServerSession;
+
+// Requests to the HTTP APIs are small, anything bigger than this is a bad
+// client.
+const MAX_BODY_SIZE: usize = 64 * 1024;
+
+/// Why a request body couldn't be read.
+#[derive(Debug, PartialEq)]
+pub enum BodyError {
+    TooLarge,
+    Read(String),
+}
+
+impl std::fmt::Display for BodyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BodyError::TooLarge => write!(f, "body too large"),
+            BodyError::Read(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// Read a request body, giving up once it's bigger than `max_size`.
+pub async fn read_body(session: &mut ServerSession, max_size: usize) -> Result<Vec<u8>, BodyError> {
+    let mut body = Vec::new();
+    while let Some(bytes) = session
+        .read_request_body()
+        .await
+        .map_err(|e| BodyError::Read(e.to_string()))?
+    {
+        if body.len() + bytes.len() > max_size {
+            return Err(BodyError::TooLarge);
+        }
+        body.extend_from_slice(&bytes);
+    }
+    Ok(body)
+}
+
+/// Read and parse a JSON request body.
+pub async fn read_json<T: serde::de::DeserializeOwned>(
+    session: &mut ServerSession,
+) -> Result<T, String> {
+    let body = read_body(session, MAX_BODY_SIZE)
+        .await
+        .map_err(|e| e.to_string())?;
+    serde_json::from_slice(&body).map_err(|e| e.to_string())
+}
+
+/// Compare tokens without giving away how much of them matched.
+pub fn same_token(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |acc, (x, y)| acc | (x ^ y))
+            == 0
+}
+
+/// Whether a request carries `token` as a bearer token.
+pub fn has_bearer(session: &ServerSession, token: &str) -> bool {
+    session
+        .req_header()
+        .headers
+        .get("Authorization")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|a| a.strip_prefix("Bearer "))
+        .is_some_and(|given| same_token(given, token))
+}
+
+pub fn json_response(status: StatusCode, body: String) -> Response<Vec<u8>> {
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .header("Content-Length", body.len())
+        .body(body.into_bytes())
+        .unwrap()
+}
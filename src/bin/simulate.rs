@@ -0,0 +1,48 @@
+use server::optimizer::Optimizer;
+use server::rebalance;
+use server::rebalance::RebalanceStrategy;
+use server::simulation::Scenario;
+use server::simulation::Simulation;
+use server::simulation::TraceEntry;
+use std::io::BufRead;
+
+/// Replay a request trace and membership events against the slice
+/// assignments and print a JSON report per tick:
+///
+/// simulate <scenario.json> [trace.jsonl] [tick ms] [strategy]
+///
+/// The trace defaults to requests.jsonl, one `{"key", "timestamp", "cost"}`
+/// per line. Ticks are a second by default. The strategy is "greedy",
+/// "optimizer" or "none" to not rebalance, the optimizer is configured with
+/// SLICED_OPTIMIZER_CONFIG like the server.
+pub fn main() {
+    let mut args = std::env::args().skip(1);
+    let scenario_path = args.next().expect("Scenario file required");
+    let trace_path = args.next().unwrap_or("requests.jsonl".to_string());
+    let tick_length: i64 = args.next().map_or(1000, |t| t.parse().unwrap());
+    let strategy_name = args.next().unwrap_or("greedy".to_string());
+
+    let scenario: Scenario =
+        serde_json::from_str(&std::fs::read_to_string(scenario_path).unwrap()).unwrap();
+    let trace = std::io::BufReader::new(std::fs::File::open(trace_path).unwrap())
+        .lines()
+        .map(|line| line.unwrap())
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| serde_json::from_str::<TraceEntry>(&line).unwrap());
+
+    let strategy: Option<Box<dyn RebalanceStrategy>> = match strategy_name.as_str() {
+        "none" => None,
+        "optimizer" => Some(match std::env::var("SLICED_OPTIMIZER_CONFIG") {
+            Ok(path) => Box::new(
+                serde_json::from_str::<Optimizer>(&std::fs::read_to_string(path).unwrap()).unwrap(),
+            ),
+            Err(_) => Box::new(Optimizer::default()),
+        }),
+        name => Some(rebalance::strategy(name).expect("Unknown rebalance strategy")),
+    };
+
+    let mut simulation = Simulation::new(scenario, tick_length, strategy.as_deref());
+    for report in simulation.run(trace) {
+        println!("{}", serde_json::to_string(&report).unwrap());
+    }
+}
@@ -0,0 +1,252 @@
+use crate::slice_assignments::SliceAssignments;
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+const MINUTE_MS: i64 = 60_000;
+
+/// Limits on how fast slices move between servers. Zero means unlimited.
+#[derive(Debug, Clone)]
+pub struct ChurnBudget {
+    /// Slices moved in any one minute.
+    pub max_moves_per_minute: usize,
+    /// Moves still in flight, a move is in flight for `in_flight_for` after
+    /// it's made while the new server warms up.
+    pub max_in_flight: usize,
+    pub in_flight_for: Duration,
+    /// The fraction of a server's slices that can move off it in any one
+    /// minute, at least one slice can always move.
+    pub max_server_fraction: f64,
+}
+
+impl Default for ChurnBudget {
+    fn default() -> Self {
+        Self {
+            max_moves_per_minute: 0,
+            max_in_flight: 0,
+            in_flight_for: Duration::from_secs(30),
+            max_server_fraction: 0.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct MoveRecord {
+    pub slice: u16,
+    pub from: SocketAddr,
+    pub to: SocketAddr,
+    pub at: i64,
+}
+
+/// Recent moves and moves waiting for budget, persisted alongside the slice
+/// assignments so every load balancer shares one budget. Timestamps are
+/// milliseconds since the epoch.
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Churn {
+    /// Moves made recently, oldest first.
+    pub moves: Vec<MoveRecord>,
+    /// Moves that didn't fit in the budget, oldest first. `at` is when the move
+    /// was first wanted.
+    pub deferred: Vec<MoveRecord>,
+    /// When each slice last moved.
+    #[serde(default)]
+    pub last_moved: BTreeMap<u16, i64>,
+}
+
+impl ChurnBudget {
+    fn allows(
+        &self,
+        churn: &Churn,
+        before: &SliceAssignments,
+        from: &SocketAddr,
+        now: i64,
+    ) -> bool {
+        let last_minute = churn.moves.iter().filter(|m| now - m.at < MINUTE_MS);
+        if self.max_moves_per_minute > 0 && last_minute.clone().count() >= self.max_moves_per_minute
+        {
+            return false;
+        }
+        let in_flight_for = self.in_flight_for.as_millis() as i64;
+        if self.max_in_flight > 0
+            && churn
+                .moves
+                .iter()
+                .filter(|m| now - m.at < in_flight_for)
+                .count()
+                >= self.max_in_flight
+        {
+            return false;
+        }
+        if self.max_server_fraction <= 0.0 {
+            return true;
+        }
+        // Relative to what the server had at the start of the minute, moves
+        // made now are already reflected in `before`.
+        let moved: Vec<_> = last_minute.filter(|m| m.from == *from).collect();
+        let earlier = moved.iter().filter(|m| m.at < now).count();
+        let max_from_server = (((before.slice_count(from) + earlier) as f64
+            * self.max_server_fraction)
+            .ceil() as usize)
+            .max(1);
+        moved.len() < max_from_server
+    }
+}
+
+/// Hold back moves between `before` and `after` that don't fit in the budget,
+/// queueing them in `after.churn.deferred` to be made later. Moves queued
+/// earlier are retried first. Slices on servers that are no longer members
+/// always move since nothing can serve them where they are, but they still use
+/// up budget.
+pub fn limit_churn(
+    before: &SliceAssignments,
+    after: &mut SliceAssignments,
+    now: i64,
+    budget: &ChurnBudget,
+) {
+    let keep_for = MINUTE_MS.max(budget.in_flight_for.as_millis() as i64);
+    let mut churn = std::mem::take(&mut after.churn);
+    churn.moves.retain(|m| now - m.at < keep_for);
+
+    // Every move that's wanted, queued ones first. A slice that has been
+    // given a new destination since it was queued goes there instead.
+    let mut wanted: Vec<MoveRecord> = Vec::new();
+    for slice in after.slices() {
+        let (Some(from), Some(to)) = (before.owner(slice), after.owner(slice)) else {
+            continue;
+        };
+        if from != to {
+            let at = churn
+                .deferred
+                .iter()
+                .find(|m| m.slice == slice)
+                .map_or(now, |m| m.at);
+            wanted.push(MoveRecord {
+                slice,
+                from,
+                to,
+                at,
+            });
+        }
+    }
+    for queued in &churn.deferred {
+        let still_valid = after.owner(queued.slice) == Some(queued.from)
+            && after.servers.contains(&queued.to)
+            && after.can_take(queued.slice, &queued.to);
+        if still_valid && !wanted.iter().any(|m| m.slice == queued.slice) {
+            wanted.push(queued.clone());
+        }
+    }
+    wanted.sort_by_key(|m| (after.servers.contains(&m.from), m.at, m.slice));
+
+    // Start from where the slices were and make each move that fits.
+    for mov in &wanted {
+        if after.servers.contains(&mov.from) {
+            after.move_slice(mov.slice, mov.from).unwrap();
+        }
+    }
+    let mut deferred = Vec::new();
+    for mov in wanted {
+        let forced = !after.servers.contains(&mov.from);
+        if forced || budget.allows(&churn, before, &mov.from, now) {
+            after.move_slice(mov.slice, mov.to).unwrap();
+            churn.last_moved.insert(mov.slice, now);
+            churn.moves.push(MoveRecord { at: now, ..mov });
+        } else {
+            deferred.push(mov);
+        }
+    }
+    if !deferred.is_empty() && deferred != churn.deferred {
+        println!(
+            "{} slice move(s) deferred by the churn budget",
+            deferred.len()
+        );
+    }
+    churn.deferred = deferred;
+    after.churn = churn;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::slice_assignments::NUM_SLICES;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    fn moves_to(assignments: &mut SliceAssignments, to: SocketAddr, count: usize) {
+        let slices: Vec<_> = (0..NUM_SLICES)
+            .filter(|&s| assignments.owner(s) != Some(to))
+            .take(count)
+            .collect();
+        for slice in slices {
+            assignments.move_slice(slice, to).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_moves_per_minute() {
+        let budget = ChurnBudget {
+            max_moves_per_minute: 5,
+            ..Default::default()
+        };
+        let before = SliceAssignments::new(vec![addr(1), addr(2), addr(3)]);
+        let mut after = before.clone();
+        moves_to(&mut after, addr(3), 8);
+        let target = after.clone();
+
+        limit_churn(&before, &mut after, 0, &budget);
+        assert_eq!(after.churn.moves.len(), 5);
+        assert_eq!(after.churn.deferred.len(), 3);
+
+        // Within the minute nothing else moves, after it the queue drains.
+        let before = after.clone();
+        limit_churn(&before, &mut after, 30_000, &budget);
+        assert_eq!(after.churn.deferred.len(), 3);
+        limit_churn(&before, &mut after, 60_000, &budget);
+        assert!(after.churn.deferred.is_empty());
+        assert_eq!(after.assignments, target.assignments);
+    }
+
+    #[test]
+    fn test_in_flight_and_server_fraction() {
+        let budget = ChurnBudget {
+            max_in_flight: 2,
+            in_flight_for: Duration::from_secs(10),
+            ..Default::default()
+        };
+        let before = SliceAssignments::new(vec![addr(1), addr(2)]);
+        let mut after = before.clone();
+        moves_to(&mut after, addr(2), 4);
+        limit_churn(&before, &mut after, 0, &budget);
+        assert_eq!(after.churn.deferred.len(), 2);
+        let before = after.clone();
+        limit_churn(&before, &mut after, 10_000, &budget);
+        assert!(after.churn.deferred.is_empty());
+
+        let budget = ChurnBudget {
+            max_server_fraction: 0.1,
+            ..Default::default()
+        };
+        let before = SliceAssignments::new(vec![addr(1), addr(2)]);
+        let mut after = before.clone();
+        moves_to(&mut after, addr(2), NUM_SLICES as usize);
+        limit_churn(&before, &mut after, 0, &budget);
+        let allowed = (before.slice_count(&addr(1)) as f64 * 0.1).ceil() as usize;
+        assert_eq!(after.churn.moves.len(), allowed);
+    }
+
+    #[test]
+    fn test_slices_of_removed_servers_always_move() {
+        let budget = ChurnBudget {
+            max_moves_per_minute: 1,
+            ..Default::default()
+        };
+        let before = SliceAssignments::new(vec![addr(1), addr(2), addr(3)]);
+        let mut after = before.clone();
+        after.update(vec![addr(1), addr(3)]);
+        limit_churn(&before, &mut after, 0, &budget);
+        assert_eq!(after.slice_count(&addr(2)), 0);
+        assert_eq!(after.churn.moves.len(), before.slice_count(&addr(2)));
+    }
+}
@@ -0,0 +1,351 @@
+use crate::db::DB;
+use crate::discovery::assign_servers;
+use crate::discovery::MembershipSource;
+use async_trait::async_trait;
+use pingora_error::Result;
+use pingora_load_balancing::discovery::ServiceDiscovery;
+use pingora_load_balancing::Backend;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::RwLock;
+
+/// Whether the servers a source reports should be added to, or removed from,
+/// the membership set.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SourceMode {
+    Include,
+    Exclude,
+}
+
+/// What to do with a source's servers when it fails to report.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OnError {
+    /// Keep using the servers from the last successful report.
+    KeepLast,
+    /// Treat the source as empty.
+    Drop,
+}
+
+pub struct Source {
+    pub name: String,
+    /// When sources disagree about a server the one with the highest
+    /// precedence wins. On a tie, exclusion wins.
+    pub precedence: i32,
+    pub mode: SourceMode,
+    pub on_error: OnError,
+    pub source: Box<dyn MembershipSource + Send + Sync>,
+}
+
+/// Configuration for a [CompositeDiscovery], eg:
+///
+/// ```json
+/// {"sources": [
+///     {"name": "fleet", "type": "dns", "port": 9999},
+///     {"name": "canaries", "type": "file", "path": "canaries.json", "precedence": 10},
+///     {"name": "blocked", "type": "file", "path": "blocked.json", "mode": "exclude", "precedence": 100}
+/// ]}
+/// ```
+#[derive(Debug, serde::Deserialize)]
+pub struct CompositeConfig {
+    pub sources: Vec<SourceConfig>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct SourceConfig {
+    pub name: String,
+    #[serde(default)]
+    pub precedence: i32,
+    #[serde(default = "default_mode")]
+    pub mode: SourceMode,
+    #[serde(default = "default_on_error")]
+    pub on_error: OnError,
+    #[serde(flatten)]
+    pub kind: SourceKind,
+}
+
+fn default_mode() -> SourceMode {
+    SourceMode::Include
+}
+
+fn default_on_error() -> OnError {
+    OnError::KeepLast
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum SourceKind {
+    Dns {
+        port: u16,
+    },
+    File {
+        path: PathBuf,
+    },
+    Registration {
+        port: u16,
+        #[serde(default = "default_ttl_secs")]
+        ttl_secs: u64,
+    },
+}
+
+fn default_ttl_secs() -> u64 {
+    5
+}
+
+/// Which source decided a server's membership.
+#[derive(Clone, Debug, PartialEq, serde::Serialize)]
+pub struct Contribution {
+    pub source: String,
+    /// Every source that reported the server, including excluding ones.
+    pub seen_in: Vec<String>,
+}
+
+/// The source that contributed each current member, shared with the admin
+/// API.
+pub type Contributions = Arc<RwLock<BTreeMap<String, Contribution>>>;
+
+/// Merges the membership of several sources, eg. DNS for the main fleet plus a
+/// file with a few pinned canaries, into one set of servers.
+pub struct CompositeDiscovery {
+    sources: Vec<Source>,
+    db: DB,
+    last_reports: RwLock<HashMap<String, BTreeSet<String>>>,
+    // Logged when it changes.
+    contributions: Contributions,
+}
+
+impl CompositeDiscovery {
+    pub fn new(sources: Vec<Source>, db: DB) -> Self {
+        Self {
+            sources,
+            db,
+            last_reports: RwLock::new(HashMap::new()),
+            contributions: Arc::new(RwLock::new(BTreeMap::new())),
+        }
+    }
+
+    /// Which source each member came from, as of the last discovery.
+    pub fn contributions(&self) -> Contributions {
+        self.contributions.clone()
+    }
+
+    async fn report(&self, source: &Source) -> BTreeSet<String> {
+        match source.source.members().await {
+            Ok(members) => {
+                self.last_reports
+                    .write()
+                    .unwrap()
+                    .insert(source.name.clone(), members.clone());
+                members
+            }
+            Err(e) => {
+                println!("discovery source {} failed: {}", source.name, e);
+                match source.on_error {
+                    OnError::KeepLast => self
+                        .last_reports
+                        .read()
+                        .unwrap()
+                        .get(&source.name)
+                        .cloned()
+                        .unwrap_or_default(),
+                    OnError::Drop => BTreeSet::new(),
+                }
+            }
+        }
+    }
+}
+
+/// Resolve which servers are members given each source's report.
+fn merge(sources: &[Source], reports: &[BTreeSet<String>]) -> BTreeMap<String, Contribution> {
+    // Server -> (precedence, mode, source name) of the winning source so far.
+    let mut winners: BTreeMap<&String, (i32, SourceMode, &String)> = BTreeMap::new();
+    let mut seen_in: BTreeMap<&String, Vec<String>> = BTreeMap::new();
+    for (source, report) in sources.iter().zip(reports) {
+        for server in report {
+            seen_in.entry(server).or_default().push(source.name.clone());
+            let candidate = (source.precedence, source.mode, &source.name);
+            winners
+                .entry(server)
+                .and_modify(|winner| {
+                    if candidate.0 > winner.0
+                        || (candidate.0 == winner.0 && candidate.1 == SourceMode::Exclude)
+                    {
+                        *winner = candidate;
+                    }
+                })
+                .or_insert(candidate);
+        }
+    }
+    winners
+        .into_iter()
+        .filter(|(_, (_, mode, _))| *mode == SourceMode::Include)
+        .map(|(server, (_, _, source))| {
+            (
+                server.clone(),
+                Contribution {
+                    source: source.clone(),
+                    seen_in: seen_in.remove(server).unwrap_or_default(),
+                },
+            )
+        })
+        .collect()
+}
+
+#[async_trait]
+impl MembershipSource for CompositeDiscovery {
+    async fn members(&self) -> Result<BTreeSet<String>> {
+        let mut reports = Vec::with_capacity(self.sources.len());
+        for source in &self.sources {
+            reports.push(self.report(source).await);
+        }
+        let contributions = merge(&self.sources, &reports);
+        let members = contributions.keys().cloned().collect();
+
+        let mut current = self.contributions.write().unwrap();
+        if *current != contributions {
+            for (server, contribution) in &contributions {
+                println!(
+                    "member {} from {} (seen in {:?})",
+                    server, contribution.source, contribution.seen_in
+                );
+            }
+            *current = contributions;
+        }
+        Ok(members)
+    }
+}
+
+#[async_trait]
+impl ServiceDiscovery for CompositeDiscovery {
+    async fn discover(&self) -> Result<(BTreeSet<Backend>, HashMap<u64, bool>)> {
+        assign_servers(&self.db, self.members().await?).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pingora_error::Error;
+    use pingora_error::ErrorType::InternalError;
+    use std::sync::{Arc, Mutex};
+
+    struct Fixed(Arc<Mutex<Option<Vec<&'static str>>>>);
+
+    #[async_trait]
+    impl MembershipSource for Fixed {
+        async fn members(&self) -> Result<BTreeSet<String>> {
+            match self.0.lock().unwrap().as_ref() {
+                Some(members) => Ok(members.iter().map(|m| m.to_string()).collect()),
+                None => Error::e_explain(InternalError, "source down"),
+            }
+        }
+    }
+
+    fn source(
+        name: &str,
+        precedence: i32,
+        mode: SourceMode,
+        members: Option<Vec<&'static str>>,
+    ) -> (Source, Arc<Mutex<Option<Vec<&'static str>>>>) {
+        let members = Arc::new(Mutex::new(members));
+        (
+            Source {
+                name: name.to_string(),
+                precedence,
+                mode,
+                on_error: OnError::KeepLast,
+                source: Box::new(Fixed(members.clone())),
+            },
+            members,
+        )
+    }
+
+    #[tokio::test]
+    async fn test_merge_sources() {
+        let (dns, _) = source(
+            "dns",
+            0,
+            SourceMode::Include,
+            Some(vec!["127.0.0.1:8001", "127.0.0.1:8002"]),
+        );
+        let (canaries, _) = source(
+            "canaries",
+            10,
+            SourceMode::Include,
+            Some(vec!["127.0.0.1:8002", "127.0.0.1:8003"]),
+        );
+        let (blocked, _) = source(
+            "blocked",
+            5,
+            SourceMode::Exclude,
+            Some(vec!["127.0.0.1:8001", "127.0.0.1:8003"]),
+        );
+        let db = DB::new(true).await.unwrap();
+        let discovery = CompositeDiscovery::new(vec![dns, canaries, blocked], db);
+
+        let members = discovery.members().await.unwrap();
+        // 8001 is excluded, 8003 is excluded at a lower precedence than the
+        // canaries include.
+        assert_eq!(
+            members,
+            BTreeSet::from(["127.0.0.1:8002".to_string(), "127.0.0.1:8003".to_string()])
+        );
+        // As shown by the admin API.
+        let contributions = discovery.contributions();
+        assert_eq!(
+            serde_json::to_value(&*contributions.read().unwrap()).unwrap(),
+            serde_json::json!({
+                "127.0.0.1:8002": {"source": "canaries", "seen_in": ["dns", "canaries"]},
+                "127.0.0.1:8003": {"source": "canaries", "seen_in": ["canaries", "blocked"]},
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_failed_source_keeps_last_report() {
+        let (dns, dns_members) =
+            source("dns", 0, SourceMode::Include, Some(vec!["127.0.0.1:8001"]));
+        let (file, _) = source("file", 0, SourceMode::Include, Some(vec!["127.0.0.1:8002"]));
+        let db = DB::new(true).await.unwrap();
+        let discovery = CompositeDiscovery::new(vec![dns, file], db);
+        assert_eq!(discovery.members().await.unwrap().len(), 2);
+
+        *dns_members.lock().unwrap() = None;
+        assert_eq!(discovery.members().await.unwrap().len(), 2);
+
+        let (backends, _) = discovery.discover().await.unwrap();
+        assert_eq!(backends.len(), 2);
+    }
+
+    #[test]
+    fn test_parse_config() {
+        let config: CompositeConfig = serde_json::from_str(
+            r#"{"sources": [
+                {"name": "fleet", "type": "dns", "port": 9999},
+                {"name": "canaries", "type": "file", "path": "canaries.json", "precedence": 10},
+                {"name": "blocked", "type": "registration", "port": 7000, "mode": "exclude", "on_error": "drop"}
+            ]}"#,
+        )
+        .unwrap();
+        assert_eq!(config.sources.len(), 3);
+        assert!(matches!(
+            config.sources[0].kind,
+            SourceKind::Dns { port: 9999 }
+        ));
+        assert_eq!(config.sources[0].mode, SourceMode::Include);
+        assert_eq!(config.sources[1].precedence, 10);
+        assert!(matches!(
+            config.sources[2].kind,
+            SourceKind::Registration {
+                port: 7000,
+                ttl_secs: 5
+            }
+        ));
+        assert_eq!(config.sources[2].mode, SourceMode::Exclude);
+        assert_eq!(config.sources[2].on_error, OnError::Drop);
+    }
+}
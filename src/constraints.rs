@@ -0,0 +1,102 @@
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::net::SocketAddr;
+
+/// Rules for where slices may be placed, persisted alongside the slice
+/// assignments, eg:
+///
+/// ```json
+/// {
+///     "pins": {"12": "10.0.0.5:8000"},
+///     "forbidden": {"40": ["10.0.0.6:8000"]},
+///     "max_slices": {"10.0.0.7:8000": 10}
+/// }
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Constraints {
+    /// Slices that must be on a specific server, eg. tenants that need
+    /// dedicated hardware.
+    #[serde(default)]
+    pub pins: BTreeMap<u16, SocketAddr>,
+    /// Servers a slice must never be placed on.
+    #[serde(default)]
+    pub forbidden: BTreeMap<u16, BTreeSet<SocketAddr>>,
+    /// The most slices a server may own.
+    #[serde(default)]
+    pub max_slices: BTreeMap<SocketAddr, usize>,
+}
+
+impl Constraints {
+    pub fn is_forbidden(&self, slice: u16, server: &SocketAddr) -> bool {
+        self.forbidden
+            .get(&slice)
+            .is_some_and(|servers| servers.contains(server))
+    }
+
+    pub fn limit(&self, server: &SocketAddr) -> usize {
+        self.max_slices.get(server).copied().unwrap_or(usize::MAX)
+    }
+}
+
+/// A constraint that the current assignments don't meet because there was
+/// nowhere to put a slice that would satisfy it.
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Violation {
+    /// The slice is pinned to a server that isn't a member.
+    PinnedServerMissing { slice: u16, server: SocketAddr },
+    /// The slice is pinned to a server that is cordoned, it stays there
+    /// anyway.
+    PinnedServerCordoned { slice: u16, server: SocketAddr },
+    /// More slices are pinned to the server than its limit.
+    PinnedOverLimit {
+        server: SocketAddr,
+        pinned: usize,
+        max: usize,
+    },
+    /// The slice is on a server it shouldn't be on.
+    Misplaced { slice: u16, server: SocketAddr },
+    /// The server owns more slices than its limit.
+    OverLimit {
+        server: SocketAddr,
+        slices: usize,
+        max: usize,
+    },
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Violation::PinnedServerMissing { slice, server } => write!(
+                f,
+                "slice {} is pinned to {} which isn't a member",
+                slice, server
+            ),
+            Violation::PinnedServerCordoned { slice, server } => {
+                write!(
+                    f,
+                    "slice {} is pinned to {} which is cordoned",
+                    slice, server
+                )
+            }
+            Violation::PinnedOverLimit {
+                server,
+                pinned,
+                max,
+            } => write!(
+                f,
+                "{} slices are pinned to {}, more than its limit of {}",
+                pinned, server, max
+            ),
+            Violation::Misplaced { slice, server } => {
+                write!(f, "slice {} can't be on {}", slice, server)
+            }
+            Violation::OverLimit {
+                server,
+                slices,
+                max,
+            } => write!(f, "{} owns {} slices, more than {}", server, slices, max),
+        }
+    }
+}
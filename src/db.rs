@@ -1,6 +1,18 @@
+use crate::churn::limit_churn;
+use crate::churn::ChurnBudget;
+use crate::constraints::Constraints;
+use crate::constraints::Violation;
+use crate::health_check::ServerStates;
+use crate::membership::Damping;
+use crate::planner::apply_moves;
+use crate::planner::ApplyError;
+use crate::planner::Plan;
+use crate::selection::SharedAssignments;
+use crate::slice_assignments::Cordon;
 use crate::slice_assignments::SliceAssignments;
 use libsql::Builder;
 use std::collections::BTreeSet;
+use std::net::SocketAddr;
 
 const ASSIGNMENTS_TABLE: &str = "
 CREATE TABLE IF NOT EXISTS assignments (
@@ -9,11 +21,36 @@ CREATE TABLE IF NOT EXISTS assignments (
     data TEXT NOT NULL
 )";
 
+// How many times to retry writing the assignments when they keep being
+// changed by someone else in between reading and writing them.
+const MAX_WRITE_ATTEMPTS: usize = 10;
+
+fn write_contended() -> libsql::Error {
+    // SQLITE_BUSY
+    libsql::Error::SqliteFailure(
+        5,
+        "assignments kept changing while being written".to_string(),
+    )
+}
+
+#[derive(Clone)]
 pub struct DB {
     pub conn: libsql::Connection,
+    pub damping: Damping,
+    /// How many slices to move off draining servers each time the servers are
+    /// updated.
+    pub drain_rate: usize,
+    /// Limits how fast slices move, whatever the reason for moving them.
+    pub churn_budget: ChurnBudget,
+    /// The assignments as of the last read or write, shared with routing.
+    pub assignments: SharedAssignments,
+    /// The health and usage of each server, which isn't stored but outlives
+    /// the backends built from the assignments.
+    pub states: ServerStates,
 }
 
-fn new_timestamp() -> i64 {
+/// Milliseconds since the epoch.
+pub fn new_timestamp() -> i64 {
     std::time::SystemTime::now()
         .duration_since(std::time::UNIX_EPOCH)
         .unwrap()
@@ -33,7 +70,14 @@ impl DB {
             ()
         ).await?;
 
-        Ok(Self { conn })
+        Ok(Self {
+            conn,
+            damping: Damping::default(),
+            drain_rate: 1,
+            churn_budget: ChurnBudget::default(),
+            assignments: SharedAssignments::default(),
+            states: ServerStates::default(),
+        })
     }
 
     pub async fn update_servers(
@@ -41,17 +85,174 @@ impl DB {
         servers: BTreeSet<String>,
     ) -> Result<SliceAssignments, libsql::Error> {
         let (mut assignments, timestamp) = self.get_assignments().await?;
-        let servers: Vec<_> = servers.into_iter().map(|s| s.parse().unwrap()).collect();
+        let original = assignments.clone();
+        let before = serde_json::to_string(&assignments).unwrap();
+        let servers: BTreeSet<_> = servers.into_iter().map(|s| s.parse().unwrap()).collect();
         if assignments.servers.is_empty() {
-            assignments = SliceAssignments::new(servers);
+            assignments = SliceAssignments::with_constraints(
+                servers.into_iter().collect(),
+                assignments.constraints,
+            );
         } else {
+            let servers = assignments.membership.effective_servers(
+                &assignments.servers,
+                &servers,
+                new_timestamp(),
+                &self.damping,
+            );
             assignments.update(servers);
         }
+        assignments.drain(self.drain_rate);
+        limit_churn(
+            &original,
+            &mut assignments,
+            new_timestamp(),
+            &self.churn_budget,
+        );
+        // Only write when something changed, so the version stays put while
+        // membership is steady and reviewed plans can still be applied.
+        if serde_json::to_string(&assignments).unwrap() == before {
+            self.assignments.store(&assignments);
+            return Ok(assignments);
+        }
         let resp = self.write_assignments(&assignments, timestamp).await?;
         if !resp.0 {
             // Another server handled the migration, fetch the new assignments.
             assignments = self.get_assignments().await?.0;
         }
+        self.assignments.store(&assignments);
+        Ok(assignments)
+    }
+
+    /// Cordon, drain, or with `None` uncordon a server.
+    pub async fn set_cordon(
+        &self,
+        server: SocketAddr,
+        cordon: Option<Cordon>,
+    ) -> Result<SliceAssignments, libsql::Error> {
+        for _ in 0..MAX_WRITE_ATTEMPTS {
+            let (mut assignments, timestamp) = self.get_assignments().await?;
+            match cordon {
+                Some(cordon) => assignments.cordons.insert(server, cordon),
+                None => assignments.cordons.remove(&server),
+            };
+            if self.write_assignments(&assignments, timestamp).await?.0 {
+                self.assignments.store(&assignments);
+                return Ok(assignments);
+            }
+        }
+        Err(write_contended())
+    }
+
+    /// Replace the placement constraints and move slices to meet them.
+    /// Returns the constraints that can't be met.
+    pub async fn set_constraints(
+        &self,
+        constraints: Constraints,
+    ) -> Result<Vec<Violation>, libsql::Error> {
+        for _ in 0..MAX_WRITE_ATTEMPTS {
+            let (mut assignments, timestamp) = self.get_assignments().await?;
+            let original = assignments.clone();
+            assignments.constraints = constraints.clone();
+            let violations = if assignments.servers.is_empty() {
+                Vec::new()
+            } else {
+                assignments.enforce_constraints()
+            };
+            limit_churn(
+                &original,
+                &mut assignments,
+                new_timestamp(),
+                &self.churn_budget,
+            );
+            if self.write_assignments(&assignments, timestamp).await?.0 {
+                self.assignments.store(&assignments);
+                return Ok(violations);
+            }
+        }
+        Err(write_contended())
+    }
+
+    /// Split each of `slices` in two, see [SliceAssignments::split]. Splits
+    /// don't move anything so aren't subject to the churn budget.
+    pub async fn split_slices(&self, slices: &[u16]) -> Result<SliceAssignments, libsql::Error> {
+        for _ in 0..MAX_WRITE_ATTEMPTS {
+            let (mut assignments, timestamp) = self.get_assignments().await?;
+            let now = new_timestamp();
+            for &slice in slices {
+                match assignments.split(slice, now) {
+                    Ok(other) => println!("split slice {} into {} and {}", slice, slice, other),
+                    Err(e) => println!("couldn't split slice {}: {}", slice, e),
+                }
+            }
+            if self.write_assignments(&assignments, timestamp).await?.0 {
+                self.assignments.store(&assignments);
+                return Ok(assignments);
+            }
+        }
+        Err(write_contended())
+    }
+
+    /// Merge each `(slice, into)` pair, see [SliceAssignments::merge].
+    pub async fn merge_slices(
+        &self,
+        merges: &[(u16, u16)],
+    ) -> Result<SliceAssignments, libsql::Error> {
+        for _ in 0..MAX_WRITE_ATTEMPTS {
+            let (mut assignments, timestamp) = self.get_assignments().await?;
+            let now = new_timestamp();
+            for &(slice, into) in merges {
+                match assignments.merge(slice, into, now) {
+                    Ok(()) => println!("merged slice {} into {}", slice, into),
+                    Err(e) => println!("couldn't merge slice {} into {}: {}", slice, into, e),
+                }
+            }
+            if self.write_assignments(&assignments, timestamp).await?.0 {
+                self.assignments.store(&assignments);
+                return Ok(assignments);
+            }
+        }
+        Err(write_contended())
+    }
+
+    pub async fn current_assignments(&self) -> Result<SliceAssignments, libsql::Error> {
+        Ok(self.get_assignments().await?.0)
+    }
+
+    /// The current assignments and their version, which changes every time
+    /// they're written.
+    pub async fn versioned_assignments(&self) -> Result<(SliceAssignments, i64), libsql::Error> {
+        self.get_assignments().await
+    }
+
+    /// Apply a previously reviewed plan, as long as the assignments are still
+    /// at the version the plan was made against. Moves that don't fit in the
+    /// churn budget are queued.
+    pub async fn apply_plan(&self, plan: &Plan) -> Result<SliceAssignments, ApplyError> {
+        let (mut assignments, version) = self.get_assignments().await?;
+        if version != plan.version {
+            return Err(ApplyError::VersionChanged {
+                planned: plan.version,
+                current: version,
+            });
+        }
+        let original = assignments.clone();
+        apply_moves(&mut assignments, plan)?;
+        limit_churn(
+            &original,
+            &mut assignments,
+            new_timestamp(),
+            &self.churn_budget,
+        );
+        let (written, _) = self.write_assignments(&assignments, version).await?;
+        if !written {
+            let current = self.get_assignments().await?.1;
+            return Err(ApplyError::VersionChanged {
+                planned: plan.version,
+                current,
+            });
+        }
+        self.assignments.store(&assignments);
         Ok(assignments)
     }
 
@@ -62,7 +263,9 @@ impl DB {
     ) -> Result<(bool, i64), libsql::Error> {
         let json = serde_json::to_string(assignments)
             .map_err(|e| libsql::Error::ConnectionFailed(e.to_string()))?;
-        let new_timestamp = new_timestamp();
+        // The timestamp doubles as the version, so it has to change on every
+        // write even if two land within the same millisecond.
+        let new_timestamp = new_timestamp().max(timestamp + 1);
 
         let rows_affected = self
             .conn
@@ -159,4 +362,68 @@ mod tests {
 
         println!("{:?}", read_assignments.assignments);
     }
+
+    #[tokio::test]
+    async fn test_drain_server() {
+        let mut db = DB::new(true).await.expect("Failed to create DB");
+        db.drain_rate = 10;
+        let servers = BTreeSet::from(["127.0.0.1:8080".to_string(), "127.0.0.1:8081".to_string()]);
+        let draining: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+
+        let assignments = db.update_servers(servers.clone()).await.unwrap();
+        let slices = assignments.slice_count(&draining);
+        assert!(slices > 0);
+
+        db.set_cordon(draining, Some(Cordon::Draining))
+            .await
+            .unwrap();
+        let assignments = db.update_servers(servers.clone()).await.unwrap();
+        assert_eq!(
+            assignments.slice_count(&draining),
+            slices.saturating_sub(10)
+        );
+
+        let mut assignments = assignments;
+        while assignments.slice_count(&draining) > 0 {
+            assignments = db.update_servers(servers.clone()).await.unwrap();
+        }
+        assert_eq!(
+            db.current_assignments().await.unwrap().cordons[&draining],
+            Cordon::Draining
+        );
+    }
+
+    #[tokio::test]
+    async fn test_apply_plan() {
+        let db = DB::new(true).await.expect("Failed to create DB");
+        let servers = BTreeSet::from(["127.0.0.1:8080".to_string(), "127.0.0.1:8081".to_string()]);
+        db.update_servers(servers.clone()).await.unwrap();
+        let (assignments, version) = db.versioned_assignments().await.unwrap();
+
+        // Nothing changes, so the version doesn't either.
+        db.update_servers(servers.clone()).await.unwrap();
+        assert_eq!(db.versioned_assignments().await.unwrap().1, version);
+
+        let from = assignments.owner(0).unwrap();
+        let to = *assignments.servers.iter().find(|&&s| s != from).unwrap();
+        let plan = Plan {
+            version,
+            moves: vec![crate::planner::PlannedMove {
+                slice: 0,
+                from,
+                to,
+                load_delta: None,
+                benefit: None,
+            }],
+        };
+        let applied = db.apply_plan(&plan).await.unwrap();
+        assert_eq!(applied.owner(0), Some(to));
+        assert_eq!(db.current_assignments().await.unwrap().owner(0), Some(to));
+
+        // The apply changed the version, so the same plan is rejected.
+        assert!(matches!(
+            db.apply_plan(&plan).await,
+            Err(ApplyError::VersionChanged { .. })
+        ));
+    }
 }
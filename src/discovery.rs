@@ -4,12 +4,49 @@ use hickory_resolver::config::NameServerConfigGroup;
 use hickory_resolver::config::ResolverConfig;
 use hickory_resolver::config::ResolverOpts;
 use hickory_resolver::AsyncResolver;
+use pingora_error::ErrorType::InternalError;
+use pingora_error::OrErr;
 use pingora_error::Result;
 use pingora_load_balancing::discovery::ServiceDiscovery;
 use pingora_load_balancing::Backend;
 use std::collections::BTreeSet;
 use std::collections::HashMap;
 
+/// A source of worker membership. Sources only report which servers they can
+/// see, the [ServiceDiscovery] implementations turn that into slice
+/// assignments with [assign_servers].
+#[async_trait]
+pub trait MembershipSource {
+    async fn members(&self) -> Result<BTreeSet<String>>;
+}
+
+/// Update the stored slice assignments for `servers` and build backends from
+/// them.
+pub async fn assign_servers(
+    db: &DB,
+    servers: BTreeSet<String>,
+) -> Result<(BTreeSet<Backend>, HashMap<u64, bool>)> {
+    // Nothing to assign slices to, leave the stored assignments alone until
+    // servers show up.
+    if servers.is_empty() {
+        return Ok((BTreeSet::new(), HashMap::new()));
+    }
+    let assignments = db.update_servers(servers).await.unwrap();
+    db.states.retain(&assignments.servers);
+    let backends: BTreeSet<_> = assignments
+        .to_backends(&db.states)
+        .into_iter()
+        .map(|mut backend| {
+            backend.ext.insert(db.assignments.clone());
+            backend
+        })
+        .collect();
+
+    println!("backends: {:?}", backends);
+
+    Ok((backends, HashMap::new()))
+}
+
 pub struct Discovery {
     port: u16,
     db: DB,
@@ -22,8 +59,8 @@ impl Discovery {
 }
 
 #[async_trait]
-impl ServiceDiscovery for Discovery {
-    async fn discover(&self) -> Result<(BTreeSet<Backend>, HashMap<u64, bool>)> {
+impl MembershipSource for Discovery {
+    async fn members(&self) -> Result<BTreeSet<String>> {
         let resolver = AsyncResolver::tokio(
             ResolverConfig::from_parts(
                 None,
@@ -36,13 +73,17 @@ impl ServiceDiscovery for Discovery {
             ),
             ResolverOpts::default(),
         );
-        let response = resolver.txt_lookup("sliced.local.").await.unwrap();
-        let backends_set: BTreeSet<_> = response.iter().map(|b| b.to_string()).collect();
-        let assignments = self.db.update_servers(backends_set).await.unwrap();
-        let backends = assignments.to_backends();
-
-        println!("backends: {:?}", backends);
+        let response = resolver
+            .txt_lookup("sliced.local.")
+            .await
+            .or_err(InternalError, "looking up sliced.local TXT records")?;
+        Ok(response.iter().map(|b| b.to_string()).collect())
+    }
+}
 
-        return Ok((backends, HashMap::new()));
+#[async_trait]
+impl ServiceDiscovery for Discovery {
+    async fn discover(&self) -> Result<(BTreeSet<Backend>, HashMap<u64, bool>)> {
+        assign_servers(&self.db, self.members().await?).await
     }
 }
@@ -0,0 +1,228 @@
+use crate::db::DB;
+use crate::discovery::assign_servers;
+use crate::discovery::MembershipSource;
+use async_trait::async_trait;
+use notify::RecommendedWatcher;
+use notify::RecursiveMode;
+use notify::Watcher;
+use pingora_error::ErrorType::InternalError;
+use pingora_error::OrErr;
+use pingora_error::Result;
+use pingora_load_balancing::discovery::ServiceDiscovery;
+use pingora_load_balancing::Backend;
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+
+/// The contents of a workers file, either JSON:
+///
+/// ```json
+/// {"workers": ["127.0.0.1:8001", "127.0.0.1:8002"]}
+/// ```
+///
+/// or TOML if the file ends in `.toml`:
+///
+/// ```toml
+/// workers = ["127.0.0.1:8001", "127.0.0.1:8002"]
+/// ```
+#[derive(Debug, serde::Deserialize)]
+struct WorkersFile {
+    workers: Vec<SocketAddr>,
+}
+
+fn load_workers(path: &Path) -> std::result::Result<BTreeSet<String>, String> {
+    let contents = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let file: WorkersFile = if path.extension().is_some_and(|ext| ext == "toml") {
+        toml::from_str(&contents).map_err(|e| e.to_string())?
+    } else {
+        serde_json::from_str(&contents).map_err(|e| e.to_string())?
+    };
+    Ok(file.workers.iter().map(|w| w.to_string()).collect())
+}
+
+/// Reload `workers` from `path`. If the file doesn't parse or lists no
+/// workers, eg. because it's half written, the previous workers are kept.
+fn reload(workers: &RwLock<BTreeSet<String>>, path: &Path) {
+    match load_workers(path) {
+        Ok(loaded) if loaded.is_empty() => println!(
+            "{} has no workers, keeping the previous ones",
+            path.display()
+        ),
+        Ok(loaded) => {
+            println!("reloaded workers from {}: {:?}", path.display(), loaded);
+            *workers.write().unwrap() = loaded;
+        }
+        Err(e) => println!(
+            "failed to reload {}, keeping the previous workers: {}",
+            path.display(),
+            e
+        ),
+    }
+}
+
+/// Discovers backends from a static list of workers in a file. The file is
+/// watched and reloaded when it changes, see [reload].
+pub struct FileDiscovery {
+    workers: Arc<RwLock<BTreeSet<String>>>,
+    db: DB,
+    // Held so that the watch stays active.
+    _watcher: RecommendedWatcher,
+}
+
+impl FileDiscovery {
+    pub fn new(path: impl Into<PathBuf>, db: DB) -> Result<Self> {
+        let path: PathBuf = path.into();
+        let workers =
+            load_workers(&path).map_err(|e| pingora_error::Error::explain(InternalError, e))?;
+        let workers = Arc::new(RwLock::new(workers));
+
+        let watched = workers.clone();
+        let file = path.clone();
+        let mut watcher =
+            notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+                let Ok(event) = event else {
+                    return;
+                };
+                if !event
+                    .paths
+                    .iter()
+                    .any(|p| p.file_name() == file.file_name())
+                {
+                    return;
+                }
+                if event.kind.is_access() || event.kind.is_remove() {
+                    return;
+                }
+                reload(&watched, &file);
+            })
+            .or_err(InternalError, "creating file watcher")?;
+
+        // Watch the directory rather than the file, editors tend to replace
+        // files instead of writing to them which would drop a watch on the file.
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        watcher
+            .watch(dir, RecursiveMode::NonRecursive)
+            .or_err(InternalError, "watching workers file")?;
+
+        Ok(Self {
+            workers,
+            db,
+            _watcher: watcher,
+        })
+    }
+}
+
+#[async_trait]
+impl MembershipSource for FileDiscovery {
+    async fn members(&self) -> Result<BTreeSet<String>> {
+        Ok(self.workers.read().unwrap().clone())
+    }
+}
+
+#[async_trait]
+impl ServiceDiscovery for FileDiscovery {
+    async fn discover(&self) -> Result<(BTreeSet<Backend>, HashMap<u64, bool>)> {
+        assign_servers(&self.db, self.members().await?).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("sliced-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_load_workers() {
+        let dir = temp_path("load");
+        let json = dir.join("workers.json");
+        std::fs::write(
+            &json,
+            r#"{"workers": ["127.0.0.1:8001", "127.0.0.1:8002"]}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            load_workers(&json).unwrap(),
+            BTreeSet::from(["127.0.0.1:8001".to_string(), "127.0.0.1:8002".to_string()])
+        );
+
+        let toml = dir.join("workers.toml");
+        std::fs::write(&toml, r#"workers = ["127.0.0.1:8003"]"#).unwrap();
+        assert_eq!(
+            load_workers(&toml).unwrap(),
+            BTreeSet::from(["127.0.0.1:8003".to_string()])
+        );
+
+        std::fs::write(&json, r#"{"workers": ["not an address"]}"#).unwrap();
+        assert!(load_workers(&json).is_err());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_reload_on_change() {
+        let dir = temp_path("reload");
+        let path = dir.join("workers.json");
+        std::fs::write(&path, r#"{"workers": ["127.0.0.1:8001"]}"#).unwrap();
+
+        let db = DB::new(true).await.unwrap();
+        let discovery = FileDiscovery::new(&path, db).unwrap();
+        let (backends, _) = discovery.discover().await.unwrap();
+        assert_eq!(backends.len(), 1);
+
+        std::fs::write(
+            &path,
+            r#"{"workers": ["127.0.0.1:8001", "127.0.0.1:8002"]}"#,
+        )
+        .unwrap();
+        let mut backends = BTreeSet::new();
+        for _ in 0..50 {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            backends = discovery.discover().await.unwrap().0;
+            if backends.len() == 2 {
+                break;
+            }
+        }
+        assert_eq!(backends.len(), 2);
+
+        // A broken file keeps the last good list.
+        std::fs::write(&path, "{").unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(discovery.discover().await.unwrap().0.len(), 2);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_reload_keeps_workers() {
+        let dir = temp_path("keep");
+        let path = dir.join("workers.json");
+        std::fs::write(
+            &path,
+            r#"{"workers": ["127.0.0.1:8001", "127.0.0.1:8002"]}"#,
+        )
+        .unwrap();
+        let workers = RwLock::new(BTreeSet::new());
+        reload(&workers, &path);
+        assert_eq!(workers.read().unwrap().len(), 2);
+
+        for contents in ["", r#"{"workers": ["127.0.0"#, r#"{"workers": []}"#] {
+            std::fs::write(&path, contents).unwrap();
+            reload(&workers, &path);
+            assert_eq!(workers.read().unwrap().len(), 2);
+        }
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
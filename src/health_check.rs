@@ -1,3 +1,6 @@
+use crate::selection::SharedAssignments;
+use crate::slice_assignments::NUM_SLICES;
+use crate::upstream_tls::UpstreamTls;
 use async_trait::async_trait;
 use pingora_core::connectors::http::Connector as HttpConnector;
 use pingora_core::upstreams::peer::{HttpPeer, Peer};
@@ -5,16 +8,78 @@ use pingora_core::Error;
 use pingora_core::Result;
 use pingora_error::ErrorType::CustomCode;
 use pingora_http::RequestHeader;
+use pingora_http::ResponseHeader;
 use pingora_load_balancing::health_check::HealthCheck;
 use pingora_load_balancing::Backend;
+use std::collections::BTreeMap;
 use std::collections::HashMap;
+use std::net::SocketAddr;
 use std::sync::{Arc, RwLock};
 use std::time::Duration;
 
+/// Checks a response from a worker, on top of its status code.
+pub type Validator = Box<dyn Fn(&ResponseHeader) -> Result<()> + Send + Sync>;
+
+/// How workers are health checked, eg:
+///
+/// ```json
+/// {"path": "/health", "port": 9000, "expected_statuses": [200, 204], "consecutive_failure": 3}
+/// ```
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default)]
+pub struct HealthCheckConfig {
+    /// Sent as the Host header, and used for SNI with `tls`.
+    pub host: String,
+    pub tls: bool,
+    pub method: String,
+    pub path: String,
+    /// Any other status code means the worker is unhealthy.
+    pub expected_statuses: Vec<u16>,
+    pub headers: BTreeMap<String, String>,
+    /// How many checks in a row have to pass before an unhealthy worker is
+    /// healthy again...
+    pub consecutive_success: usize,
+    /// ...and fail before a healthy one is unhealthy.
+    pub consecutive_failure: usize,
+    pub connect_timeout_ms: u64,
+    pub read_timeout_ms: u64,
+    /// Check this port rather than the one requests are proxied to.
+    pub port: Option<u16>,
+    pub reuse_connection: bool,
+    /// Usage reports bigger than this are dropped without being parsed.
+    pub max_body_bytes: usize,
+    /// Take usage from the response body. Turn this off when workers push
+    /// their usage instead, see [crate::usage_ingest], so checks are only
+    /// about liveness.
+    pub collect_usage: bool,
+}
+
+impl Default for HealthCheckConfig {
+    fn default() -> Self {
+        Self {
+            host: "sliced.local".to_string(),
+            tls: false,
+            method: "GET".to_string(),
+            path: "/".to_string(),
+            expected_statuses: vec![200],
+            headers: BTreeMap::new(),
+            consecutive_success: 1,
+            consecutive_failure: 1,
+            connect_timeout_ms: 1000,
+            read_timeout_ms: 1000,
+            port: None,
+            reuse_connection: false,
+            max_body_bytes: 64 * 1024,
+            collect_usage: true,
+        }
+    }
+}
+
 pub struct WorkerHealthCheck {
     // Health check configuration
     consecutive_success: usize,
     consecutive_failure: usize,
+    expected_statuses: Vec<u16>,
 
     // HTTP specific fields
     peer_template: HttpPeer,
@@ -22,48 +87,112 @@ pub struct WorkerHealthCheck {
     req: RequestHeader,
     connector: HttpConnector,
     port_override: Option<u16>,
+    max_body_bytes: usize,
+    collect_usage: bool,
+
+    /// Extra checks on the response, see [Validator].
+    pub validator: Option<Validator>,
+
+    /// Half-life of the moving average applied to reported slice load, zero
+    /// uses each sample as is.
+    pub usage_half_life: Duration,
 }
 
 impl Default for WorkerHealthCheck {
     fn default() -> Self {
-        // Create default HTTP request
-        let mut req = RequestHeader::build("GET", b"/health", None).unwrap();
-        req.append_header("Host", "localhost").unwrap();
-
-        // Create peer template
-        let mut peer_template = HttpPeer::new("0.0.0.0:1", false, String::new());
-        peer_template.options.connection_timeout = Some(Duration::from_secs(1));
-        peer_template.options.read_timeout = Some(Duration::from_secs(1));
-
-        WorkerHealthCheck {
-            consecutive_success: 1,
-            consecutive_failure: 1,
-            peer_template,
-            connector: HttpConnector::new(None),
-            reuse_connection: false,
-            req,
-            port_override: None,
-        }
+        WorkerHealthCheck::from_config(&HealthCheckConfig {
+            host: "localhost".to_string(),
+            path: "/health".to_string(),
+            ..Default::default()
+        })
+        .unwrap()
     }
 }
 
 impl WorkerHealthCheck {
     pub fn new(host: &str, tls: bool) -> Self {
-        let mut req = RequestHeader::build("GET", b"/", None).unwrap();
-        req.append_header("Host", host).unwrap();
-        let sni = if tls { host.into() } else { String::new() };
-        let mut peer_template = HttpPeer::new("0.0.0.0:1", tls, sni);
-        peer_template.options.connection_timeout = Some(Duration::from_secs(1));
-        peer_template.options.read_timeout = Some(Duration::from_secs(1));
-        WorkerHealthCheck {
-            consecutive_success: 1,
-            consecutive_failure: 1,
+        WorkerHealthCheck::from_config(&HealthCheckConfig {
+            host: host.to_string(),
+            tls,
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    pub fn from_config(config: &HealthCheckConfig) -> Result<Self> {
+        let mut req = RequestHeader::build(config.method.as_str(), config.path.as_bytes(), None)?;
+        req.append_header("Host", config.host.as_str())?;
+        for (name, value) in &config.headers {
+            req.insert_header(name.clone(), value.as_str())?;
+        }
+        let sni = if config.tls {
+            config.host.clone()
+        } else {
+            String::new()
+        };
+        let mut peer_template = HttpPeer::new("0.0.0.0:1", config.tls, sni);
+        peer_template.options.connection_timeout =
+            Some(Duration::from_millis(config.connect_timeout_ms));
+        peer_template.options.read_timeout = Some(Duration::from_millis(config.read_timeout_ms));
+        Ok(WorkerHealthCheck {
+            consecutive_success: config.consecutive_success.max(1),
+            consecutive_failure: config.consecutive_failure.max(1),
+            expected_statuses: config.expected_statuses.clone(),
             peer_template,
             connector: HttpConnector::new(None),
-            reuse_connection: false,
+            reuse_connection: config.reuse_connection,
             req,
-            port_override: None,
+            port_override: config.port,
+            max_body_bytes: config.max_body_bytes,
+            collect_usage: config.collect_usage,
+            validator: None,
+            usage_half_life: Duration::ZERO,
+        })
+    }
+
+    /// Check workers over TLS, with the same settings as proxied requests.
+    /// SNI defaults to the Host header.
+    pub fn set_tls(&mut self, tls: &UpstreamTls) {
+        if self.peer_template.sni.is_empty() {
+            if let Some(host) = self.req.headers.get("Host").and_then(|h| h.to_str().ok()) {
+                self.peer_template.sni = host.to_string();
+            }
         }
+        tls.apply(&mut self.peer_template);
+    }
+
+    /// Record the result of checking `target`, along with any usage it
+    /// reported.
+    fn set_health(&self, target: &Backend, success: bool, usage: Option<Usage>) {
+        let health = target
+            .ext
+            .get::<HealthStatus>()
+            .expect("health status not found");
+        health
+            .inner
+            .write()
+            .unwrap()
+            .record_check(success, self.health_threshold(success));
+        // Without a new sample the last one stays until it's too old to use,
+        // see [HealthStatusInner::fresh_usage].
+        if let Some(usage) = usage.filter(|_| success) {
+            record_usage(health, usage, self.usage_half_life);
+        }
+    }
+
+    /// Whether a response means the worker is healthy.
+    fn validate(&self, resp: &ResponseHeader) -> Result<()> {
+        let status = resp.status.as_u16();
+        if !self.expected_statuses.contains(&status) {
+            return Error::e_explain(
+                CustomCode("unexpected status code", status),
+                "during http healthcheck",
+            );
+        }
+        if let Some(validator) = self.validator.as_ref() {
+            validator(resp)?;
+        }
+        Ok(())
     }
 }
 
@@ -77,6 +206,31 @@ pub struct HealthStatusInner {
     pub is_healthy: bool,
     pub last_check: std::time::Instant,
     pub usage: Option<Usage>,
+    /// When `usage` was measured, the middle of the worker's measurement
+    /// window. Used to weigh samples when smoothing.
+    pub usage_at: Option<std::time::Instant>,
+    /// When `usage` was received, which is what it goes stale from.
+    pub received_at: Option<std::time::Instant>,
+    /// Smoothed usage of each slice, `usage` holds these with loads rounded.
+    pub smoothed: HashMap<u16, SmoothedSlice>,
+    pub reports: ReportCounts,
+    /// Health checks passed in a row...
+    pub consecutive_successes: usize,
+    /// ...and failed.
+    pub consecutive_failures: usize,
+}
+
+/// Usage reports from a server that were dropped, in whole or in part.
+#[derive(Clone, Debug, Default, PartialEq, serde::Serialize)]
+pub struct ReportCounts {
+    /// Bigger than `max_body_bytes`.
+    pub oversized: u64,
+    /// Not valid usage JSON.
+    pub malformed: u64,
+    /// A version of the usage schema we don't understand.
+    pub unsupported_version: u64,
+    /// Slices in otherwise valid reports that the server doesn't own.
+    pub rejected_slices: u64,
 }
 
 impl HealthStatus {
@@ -86,34 +240,278 @@ impl HealthStatus {
                 is_healthy: true, // default to healthy
                 last_check: std::time::Instant::now(),
                 usage: None,
+                usage_at: None,
+                received_at: None,
+                smoothed: HashMap::new(),
+                reports: ReportCounts::default(),
+                consecutive_successes: 0,
+                consecutive_failures: 0,
             })),
         }
     }
 }
 
-fn set_health(target: &Backend, is_healthy: bool, usage: Option<Usage>) {
-    let health = target
-        .ext
-        .get::<HealthStatus>()
-        .expect("health status not found");
+impl HealthStatusInner {
+    /// Count a health check result. `is_healthy` only changes once
+    /// `threshold` results in a row disagree with it.
+    pub fn record_check(&mut self, success: bool, threshold: usize) {
+        let run = if success {
+            self.consecutive_failures = 0;
+            &mut self.consecutive_successes
+        } else {
+            self.consecutive_successes = 0;
+            &mut self.consecutive_failures
+        };
+        *run += 1;
+        if *run >= threshold {
+            self.is_healthy = success;
+        }
+        self.last_check = std::time::Instant::now();
+    }
+
+    /// How long ago `usage` was received.
+    pub fn usage_age(&self) -> Option<Duration> {
+        self.received_at.map(|at| at.elapsed())
+    }
+
+    /// `usage`, unless it's older than `max_age`. Zero means usage never goes
+    /// stale.
+    pub fn fresh_usage(&self, max_age: Duration) -> Option<&Usage> {
+        let stale = !max_age.is_zero() && self.usage_age().is_some_and(|age| age > max_age);
+        self.usage.as_ref().filter(|_| !stale)
+    }
+}
+
+impl Default for HealthStatus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The health and usage of each server, kept across discovery cycles. Every
+/// [Backend] built for a server shares the server's [HealthStatus], so what
+/// the health check collects isn't lost when the backends are rebuilt.
+#[derive(Clone, Default)]
+pub struct ServerStates(Arc<RwLock<HashMap<SocketAddr, HealthStatus>>>);
+
+impl ServerStates {
+    /// The state of `server`, starting out healthy with no usage.
+    pub fn status(&self, server: SocketAddr) -> HealthStatus {
+        if let Some(status) = self.0.read().unwrap().get(&server) {
+            return status.clone();
+        }
+        self.0.write().unwrap().entry(server).or_default().clone()
+    }
+
+    pub fn get(&self, server: &SocketAddr) -> Option<HealthStatus> {
+        self.0.read().unwrap().get(server).cloned()
+    }
+
+    /// Forget servers that aren't in `servers`.
+    pub fn retain(&self, servers: &[SocketAddr]) {
+        self.0
+            .write()
+            .unwrap()
+            .retain(|server, _| servers.contains(server));
+    }
+}
+
+/// Store a usage sample from a server, whether it came with a health check or
+/// was pushed, see [crate::usage_ingest]. Samples from an unhealthy server
+/// are dropped, a failing worker's numbers aren't worth averaging in.
+pub fn record_usage(health: &HealthStatus, usage: Usage, half_life: Duration) {
     let mut state = health.inner.write().unwrap();
-    state.is_healthy = is_healthy;
-    if usage.is_some() {
-        // TODO: Might mean we're dealing with stale data
-        state.usage = usage;
+    if !state.is_healthy {
+        return;
     }
-    state.last_check = std::time::Instant::now();
+    let now = std::time::Instant::now();
+    let window = Duration::from_millis(usage.window_ms.unwrap_or(0));
+    let sampled_at = now.checked_sub(window / 2).unwrap_or(now);
+    let elapsed = state.usage_at.map_or(Duration::ZERO, |at| {
+        sampled_at.saturating_duration_since(at)
+    });
+    state.usage = Some(smooth(&mut state.smoothed, usage, elapsed, half_life));
+    state.usage_at = Some(sampled_at);
+    state.received_at = Some(now);
 }
 
+/// Smoothed load and resources of a slice.
+#[derive(Clone, Debug, Default)]
+pub struct SmoothedSlice {
+    load: f64,
+    resources: BTreeMap<String, f64>,
+}
+
+/// Exponentially weighted moving average of each slice's load, a sample from
+/// `half_life` ago counts half as much as one from now. This stops a single
+/// spike from moving slices around. The average lives in the server's
+/// [HealthStatus], so it relies on [ServerStates] keeping that across
+/// discovery cycles.
+fn smooth(
+    smoothed: &mut HashMap<u16, SmoothedSlice>,
+    sample: Usage,
+    elapsed: Duration,
+    half_life: Duration,
+) -> Usage {
+    let weight = if half_life.is_zero() {
+        1.0
+    } else {
+        1.0 - 0.5f64.powf(elapsed.as_secs_f64() / half_life.as_secs_f64())
+    };
+    let average = |value: &mut f64, sample: f64, new: bool| {
+        if new {
+            *value = sample;
+        } else {
+            *value += weight * (sample - *value);
+        }
+    };
+    // Slices that are no longer reported have moved away
+    smoothed.retain(|slice, _| sample.slices.contains_key(slice));
+    for (slice, usage) in sample.slices {
+        let new = !smoothed.contains_key(&slice);
+        let value = smoothed.entry(slice).or_default();
+        average(&mut value.load, usage.load as f64, new);
+        value
+            .resources
+            .retain(|name, _| usage.resources.contains_key(name));
+        for (name, amount) in usage.resources {
+            let new = new || !value.resources.contains_key(&name);
+            average(value.resources.entry(name).or_default(), amount, new);
+        }
+    }
+    Usage {
+        slices: smoothed
+            .iter()
+            .map(|(&slice, value)| {
+                (
+                    slice,
+                    SliceUsage {
+                        load: value.load.round() as u32,
+                        resources: value.resources.clone(),
+                    },
+                )
+            })
+            .collect(),
+        capacity: sample.capacity,
+        version: sample.version,
+        window_ms: sample.window_ms,
+    }
+}
+
+/// The usage in a report from `server`, if it's one we can use. Reports that
+/// are `oversized`, malformed or of an unsupported version are dropped, as is
+/// usage of slices `server` doesn't own, and counted in its [ReportCounts]. An
+/// empty body just means the worker doesn't report usage, as does one that
+/// isn't JSON and doesn't look like usage, eg a plain "OK". Until there are
+/// assignments to check against, only the original slices are taken.
+pub fn parse_usage(
+    health: &HealthStatus,
+    server: &SocketAddr,
+    assignments: Option<&SharedAssignments>,
+    content_type: Option<&str>,
+    body: &[u8],
+    oversized: bool,
+) -> Option<Usage> {
+    let count = |counter: fn(&mut ReportCounts) -> &mut u64, by: u64| {
+        *counter(&mut health.inner.write().unwrap().reports) += by;
+    };
+    if oversized {
+        println!("usage from {} is too big, dropping it", server);
+        count(|c| &mut c.oversized, 1);
+        return None;
+    }
+    if body.is_empty() {
+        return None;
+    }
+    let mut usage: Usage = match serde_json::from_slice(body) {
+        Ok(usage) => usage,
+        Err(e) => {
+            let json = content_type.is_some_and(|c| c.contains("json"));
+            let has_slices = serde_json::from_slice::<serde_json::Value>(body)
+                .is_ok_and(|value| value.get("slices").is_some());
+            if json || has_slices {
+                println!("malformed usage from {}: {}", server, e);
+                count(|c| &mut c.malformed, 1);
+            }
+            return None;
+        }
+    };
+    if usage.version != USAGE_VERSION {
+        println!(
+            "usage from {} is version {}, expected {}",
+            server, usage.version, USAGE_VERSION
+        );
+        count(|c| &mut c.unsupported_version, 1);
+        return None;
+    }
+    let reported = usage.slices.len();
+    usage.slices.retain(|&slice, _| {
+        assignments
+            .and_then(|a| a.owns(server, slice))
+            .unwrap_or(slice < NUM_SLICES)
+    });
+    let rejected = reported - usage.slices.len();
+    if rejected > 0 {
+        println!(
+            "dropping usage of {} slices {} doesn't own",
+            rejected, server
+        );
+        count(|c| &mut c.rejected_slices, rejected as u64);
+    }
+    Some(usage)
+}
+
+/// The version of [Usage] reports we understand. Reports without a version
+/// are taken to be this one.
+pub const USAGE_VERSION: u32 = 1;
+
 /// Usage is a map of slice index to a "load" number that can be whatever you
-/// want.
+/// want. Slices can instead report named resources alongside the server's
+/// capacity for each, eg:
+///
+/// ```json
+/// {"slices": {"0": {"resources": {"cpu": 0.5, "memory": 120}}}, "capacity": {"cpu": 4, "memory": 2048}}
+/// ```
+///
+/// in which case slices are balanced by whichever resource is closest to
+/// running out, see [crate::resources]. Usage that's been measured over a
+/// while, eg a rate over the last minute, should say so with `window_ms` so
+/// it's weighed as of the middle of that window when smoothing. Proxied requests say which
+/// slice they're for in an `X-Slice` header, which is the slice their usage
+/// should be reported under.
 #[derive(Clone, Debug, serde::Deserialize)]
 pub struct Usage {
     pub slices: HashMap<u16, SliceUsage>,
+    #[serde(default)]
+    pub capacity: BTreeMap<String, f64>,
+    #[serde(default = "usage_version")]
+    pub version: u32,
+    /// How long the worker measured this usage over, ending when it
+    /// responded.
+    #[serde(default)]
+    pub window_ms: Option<u64>,
 }
-#[derive(Clone, Debug, serde::Deserialize)]
+
+impl Default for Usage {
+    fn default() -> Self {
+        Self {
+            slices: HashMap::new(),
+            capacity: BTreeMap::new(),
+            version: USAGE_VERSION,
+            window_ms: None,
+        }
+    }
+}
+
+fn usage_version() -> u32 {
+    USAGE_VERSION
+}
+#[derive(Clone, Debug, Default, serde::Deserialize)]
 pub struct SliceUsage {
+    #[serde(default)]
     pub load: u32,
+    #[serde(default)]
+    pub resources: BTreeMap<String, f64>,
 }
 
 #[async_trait]
@@ -135,54 +533,74 @@ impl HealthCheck for WorkerHealthCheck {
             peer._address.set_port(port);
         }
 
-        // Establish HTTP session
-        let session = self.connector.get_http_session(&peer).await?;
-        let mut session = session.0;
-
-        // Send request
-        let req = Box::new(self.req.clone());
-        session.write_request_header(req).await?;
-
-        // Set read timeout if configured
-        if let Some(read_timeout) = peer.options.read_timeout {
-            session.set_read_timeout(read_timeout);
-        }
-
-        // Read response
-        session.read_response_header().await?;
-        let resp = session.response_header().expect("just read");
+        // Connection failures and timeouts count against the worker as much
+        // as a bad response does
+        let fetched: Result<_> = async {
+            // Establish HTTP session
+            let (mut session, _) = self.connector.get_http_session(&peer).await?;
 
-        // Validate response
-        // if let Some(validator) = self.validator.as_ref() {
-        //     validator(resp)?;
-        // }
-        let status = resp.status;
+            // Send request
+            let req = Box::new(self.req.clone());
+            session.write_request_header(req).await?;
 
-        let mut body: Vec<u8> = Vec::new();
+            // Set read timeout if configured
+            if let Some(read_timeout) = peer.options.read_timeout {
+                session.set_read_timeout(read_timeout);
+            }
 
-        let mut usage: Option<Usage> = None;
-        // Drain response body
-        while let Some(bytes) = session.read_response_body().await? {
-            // TODO: make sure this is the way to do this
-            // TODO: bail when it's too big?
-            body.append(&mut bytes.try_into_mut().unwrap().to_vec());
-        }
+            // Read response
+            session.read_response_header().await?;
+            let resp = session.response_header().expect("just read");
+            let valid = self.validate(resp);
 
-        if let Ok(_usage) = serde_json::from_slice::<Usage>(&body[..]) {
-            usage = Some(_usage)
+            // Read the usage report, giving up on it once it's too big
+            let mut body: Vec<u8> = Vec::new();
+            let mut oversized = false;
+            while let Some(bytes) = session.read_response_body().await? {
+                if !self.collect_usage {
+                    continue;
+                }
+                if body.len() + bytes.len() > self.max_body_bytes {
+                    oversized = true;
+                    break;
+                }
+                body.extend_from_slice(&bytes);
+            }
+            Ok((session, valid, body, oversized))
         }
+        .await;
+        let (session, valid, body, oversized) = match fetched {
+            Ok(fetched) => fetched,
+            Err(e) => {
+                self.set_health(target, false, None);
+                return Err(e);
+            }
+        };
+        let health = target.ext.get::<HealthStatus>();
+        let content_type = session
+            .response_header()
+            .and_then(|resp| resp.headers.get("Content-Type"))
+            .and_then(|v| v.to_str().ok());
+        let usage = match (self.collect_usage, health, target.addr.as_inet()) {
+            (true, Some(health), Some(server)) => parse_usage(
+                health,
+                server,
+                target.ext.get::<SharedAssignments>(),
+                content_type,
+                &body,
+                oversized,
+            ),
+            _ => None,
+        };
 
-        if status != 200 {
-            set_health(target, false, usage);
-            return Error::e_explain(
-                CustomCode("non 200 code", status.as_u16()),
-                "during http healthcheck",
-            );
+        if let Err(e) = valid {
+            self.set_health(target, false, usage);
+            return Err(e);
         }
-        set_health(target, true, usage);
+        self.set_health(target, true, usage);
 
-        // Handle connection reuse
-        if self.reuse_connection {
+        // Handle connection reuse, unless the body wasn't read to the end
+        if self.reuse_connection && !oversized {
             let idle_timeout = peer.idle_timeout();
             self.connector
                 .release_http_session(session, &peer, idle_timeout)
@@ -192,3 +610,263 @@ impl HealthCheck for WorkerHealthCheck {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn usage(load: u32) -> Usage {
+        Usage {
+            slices: HashMap::from([(
+                0,
+                SliceUsage {
+                    load,
+                    ..Default::default()
+                },
+            )]),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_config() {
+        let config: HealthCheckConfig = serde_json::from_str(
+            r#"{"method": "HEAD", "path": "/health", "expected_statuses": [200, 204], "headers": {"X-Check": "1"}, "consecutive_failure": 3}"#,
+        )
+        .unwrap();
+        let mut hc = WorkerHealthCheck::from_config(&config).unwrap();
+        assert_eq!(hc.req.method, "HEAD");
+        assert_eq!(hc.req.uri.path(), "/health");
+        assert_eq!(hc.req.headers["X-Check"], "1");
+        assert_eq!(hc.req.headers["Host"], "sliced.local");
+        assert_eq!(hc.health_threshold(false), 3);
+        assert_eq!(hc.health_threshold(true), 1);
+
+        let response = |status: u16| ResponseHeader::build(status, None).unwrap();
+        assert!(hc.validate(&response(204)).is_ok());
+        assert!(hc.validate(&response(500)).is_err());
+        hc.validator = Some(Box::new(|resp: &ResponseHeader| {
+            if resp.headers.contains_key("X-Ready") {
+                Ok(())
+            } else {
+                Error::e_explain(CustomCode("not ready", 0), "during http healthcheck")
+            }
+        }));
+        assert!(hc.validate(&response(200)).is_err());
+        let mut ready = response(200);
+        ready.insert_header("X-Ready", "1").unwrap();
+        assert!(hc.validate(&ready).is_ok());
+    }
+
+    #[test]
+    fn test_server_states() {
+        let states = ServerStates::default();
+        let server: SocketAddr = "127.0.0.1:8000".parse().unwrap();
+        let backend = |states: &ServerStates| {
+            let mut backend = Backend::new(&server.to_string()).unwrap();
+            backend.ext.insert(states.status(server));
+            backend
+        };
+
+        let hc = WorkerHealthCheck::default();
+
+        // What's collected through one backend is there for the next one.
+        hc.set_health(&backend(&states), true, Some(usage(7)));
+        let status = backend(&states).ext.get::<HealthStatus>().unwrap().clone();
+        let reported = status.inner.read().unwrap().usage.clone().unwrap();
+        assert_eq!(reported.slices[&0].load, 7);
+
+        // Usage from a failed check isn't taken.
+        hc.set_health(&backend(&states), false, Some(usage(9)));
+        assert!(!status.inner.read().unwrap().is_healthy);
+        let reported = status.inner.read().unwrap().usage.clone().unwrap();
+        assert_eq!(reported.slices[&0].load, 7);
+
+        // Usage is kept until it's too old.
+        hc.set_health(&backend(&states), true, None);
+        let state = status.inner.read().unwrap().clone();
+        assert!(state.fresh_usage(Duration::from_secs(60)).is_some());
+        assert!(state.fresh_usage(Duration::ZERO).is_some());
+        // A long measurement window doesn't make it stale on arrival.
+        hc.set_health(
+            &backend(&states),
+            true,
+            Some(Usage {
+                window_ms: Some(120_000),
+                ..usage(7)
+            }),
+        );
+        let state = status.inner.read().unwrap().clone();
+        assert!(state.usage_age().unwrap() < Duration::from_secs(30));
+        assert!(state.fresh_usage(Duration::from_secs(30)).is_some());
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(state.fresh_usage(Duration::from_millis(10)).is_none());
+        assert!(state.fresh_usage(Duration::ZERO).is_some());
+
+        states.retain(&[]);
+        assert!(states.get(&server).is_none());
+    }
+
+    #[test]
+    fn test_thresholds() {
+        let hc = WorkerHealthCheck::from_config(&HealthCheckConfig {
+            consecutive_success: 2,
+            consecutive_failure: 3,
+            ..Default::default()
+        })
+        .unwrap();
+        let mut backend = Backend::new("127.0.0.1:8000").unwrap();
+        let status = HealthStatus::new();
+        backend.ext.insert(status.clone());
+        let healthy = |results: &[bool]| {
+            for &success in results {
+                hc.set_health(&backend, success, None);
+            }
+            status.inner.read().unwrap().is_healthy
+        };
+
+        assert!(healthy(&[false, false]));
+        assert!(healthy(&[true, false, false]));
+        assert!(!healthy(&[false]));
+        assert!(!healthy(&[true, false, true]));
+        assert!(healthy(&[true]));
+    }
+
+    #[tokio::test]
+    async fn test_unreachable() {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let hc = WorkerHealthCheck::from_config(&HealthCheckConfig {
+            consecutive_failure: 2,
+            ..Default::default()
+        })
+        .unwrap();
+        let mut backend = Backend::new(&format!("127.0.0.1:{}", port)).unwrap();
+        let status = HealthStatus::new();
+        backend.ext.insert(status.clone());
+
+        // Nothing is listening, which counts as a failed check.
+        assert!(hc.check(&backend).await.is_err());
+        assert!(status.inner.read().unwrap().is_healthy);
+        assert!(hc.check(&backend).await.is_err());
+        let state = status.inner.read().unwrap();
+        assert!(!state.is_healthy);
+        assert_eq!(state.consecutive_failures, 2);
+    }
+
+    #[test]
+    fn test_parse_usage() {
+        use crate::slice_assignments::SliceAssignments;
+
+        let server: SocketAddr = "127.0.0.1:8000".parse().unwrap();
+        let other: SocketAddr = "127.0.0.1:8001".parse().unwrap();
+        let assignments = SliceAssignments::new(vec![server, other]);
+        let owned = (0..).find(|&s| assignments.owns(&server, s)).unwrap();
+        let not_owned = (0..).find(|&s| assignments.owns(&other, s)).unwrap();
+        let shared = SharedAssignments::default();
+        let status = HealthStatus::new();
+        let parse_as = |content_type, body: &[u8], oversized| {
+            parse_usage(
+                &status,
+                &server,
+                Some(&shared),
+                content_type,
+                body,
+                oversized,
+            )
+        };
+        let parse = |body: &[u8], oversized| parse_as(None, body, oversized);
+        let counts = || status.inner.read().unwrap().reports.clone();
+
+        assert!(parse(b"", false).is_none());
+        assert!(parse(b"{}", true).is_none());
+        // Not usage at all, unless it says it's JSON or has slices in it.
+        assert!(parse(b"OK", false).is_none());
+        assert!(parse_as(Some("application/json"), b"OK", false).is_none());
+        assert!(parse(br#"{"slices": 5}"#, false).is_none());
+        assert!(parse(br#"{"version": 2, "slices": {}}"#, false).is_none());
+        assert_eq!(
+            counts(),
+            ReportCounts {
+                oversized: 1,
+                malformed: 2,
+                unsupported_version: 1,
+                rejected_slices: 0,
+            }
+        );
+
+        // Only the original slices are taken until there are assignments to
+        // check against.
+        let body = format!(
+            r#"{{"slices": {{"{}": {{"load": 1}}, "{}": {{"load": 2}}, "5000": {{"load": 3}}}}}}"#,
+            owned, not_owned
+        );
+        let usage = parse(body.as_bytes(), false).unwrap();
+        assert_eq!(usage.version, USAGE_VERSION);
+        assert_eq!(usage.slices.len(), 2);
+        assert!(!usage.slices.contains_key(&5000));
+        shared.store(&assignments);
+        let usage = parse(body.as_bytes(), false).unwrap();
+        assert_eq!(usage.slices.keys().collect::<Vec<_>>(), vec![&owned]);
+        assert_eq!(counts().rejected_slices, 3);
+
+        // Split off slices are taken from their owner.
+        let mut split = assignments.clone();
+        let child = split.split(owned, 0).unwrap();
+        shared.store(&split);
+        let body = format!(r#"{{"slices": {{"{}": {{"load": 1}}}}}}"#, child);
+        assert!(parse(body.as_bytes(), false)
+            .unwrap()
+            .slices
+            .contains_key(&child));
+    }
+
+    #[test]
+    fn test_smooth() {
+        let half_life = Duration::from_secs(10);
+        let mut smoothed = HashMap::new();
+        let first = smooth(&mut smoothed, usage(100), Duration::ZERO, half_life);
+        assert_eq!(first.slices[&0].load, 100);
+
+        // A spike one half-life later only gets half the weight.
+        let spiked = smooth(&mut smoothed, usage(300), half_life, half_life);
+        assert_eq!(spiked.slices[&0].load, 200);
+
+        // Without a half-life samples are used as is, and slices that aren't
+        // reported any more are dropped.
+        let raw = smooth(
+            &mut smoothed,
+            Usage {
+                slices: HashMap::from([(
+                    1,
+                    SliceUsage {
+                        load: 5,
+                        ..Default::default()
+                    },
+                )]),
+                ..Default::default()
+            },
+            Duration::ZERO,
+            Duration::ZERO,
+        );
+        assert_eq!(raw.slices.len(), 1);
+        assert_eq!(raw.slices[&1].load, 5);
+
+        // Resources are smoothed the same way and capacity passes through.
+        let mut smoothed = HashMap::new();
+        let resources = |cpu: f64| -> Usage {
+            serde_json::from_str(&format!(
+                r#"{{"slices":{{"0":{{"resources":{{"cpu":{}}}}}}},"capacity":{{"cpu":4}}}}"#,
+                cpu
+            ))
+            .unwrap()
+        };
+        smooth(&mut smoothed, resources(1.0), Duration::ZERO, half_life);
+        let spiked = smooth(&mut smoothed, resources(3.0), half_life, half_life);
+        assert_eq!(spiked.slices[&0].resources["cpu"], 2.0);
+        assert_eq!(spiked.capacity["cpu"], 4.0);
+    }
+}
@@ -0,0 +1,25 @@
+#![deny(clippy::all)]
+
+pub mod admin;
+pub mod api;
+pub mod churn;
+pub mod composite_discovery;
+pub mod constraints;
+pub mod db;
+pub mod discovery;
+pub mod file_discovery;
+pub mod health_check;
+pub mod membership;
+pub mod optimizer;
+pub mod passive_health;
+pub mod planner;
+pub mod rebalance;
+pub mod registration;
+pub mod resources;
+pub mod selection;
+pub mod simulation;
+pub mod slice_assignments;
+pub mod split;
+pub mod traffic;
+pub mod upstream_tls;
+pub mod usage_ingest;
@@ -1,29 +1,60 @@
 #![deny(clippy::all)]
 
-mod db;
-mod discovery;
-mod health_check;
-mod selection;
-mod slice_assignments;
-use crate::db::DB;
-use crate::discovery::Discovery;
-use crate::health_check::WorkerHealthCheck;
-use crate::selection::SliceSelection;
 use async_trait::async_trait;
 use log::info;
 use pingora::prelude::Opt;
 use pingora::server::configuration::ServerConf;
-use pingora::OkOrErr;
 use pingora_core::server::Server;
 use pingora_core::services::background::background_service;
+use pingora_core::services::listening::Service;
 use pingora_core::upstreams::peer::HttpPeer;
+use pingora_core::Error;
 use pingora_core::Result;
+use pingora_http::RequestHeader;
+use pingora_http::ResponseHeader;
+use pingora_load_balancing::discovery::ServiceDiscovery;
 use pingora_load_balancing::Backends;
 use pingora_load_balancing::LoadBalancer;
 use pingora_proxy::ProxyHttp;
 use pingora_proxy::Session;
+use server::admin::AdminApi;
+use server::churn::ChurnBudget;
+use server::composite_discovery::CompositeConfig;
+use server::composite_discovery::CompositeDiscovery;
+use server::composite_discovery::Contributions;
+use server::composite_discovery::Source;
+use server::composite_discovery::SourceKind;
+use server::db::DB;
+use server::discovery::Discovery;
+use server::discovery::MembershipSource;
+use server::file_discovery::FileDiscovery;
+use server::health_check::HealthCheckConfig;
+use server::health_check::WorkerHealthCheck;
+use server::membership::Damping;
+use server::optimizer::Optimizer;
+use server::passive_health::PassiveHealth;
+use server::passive_health::PassiveHealthSettings;
+use server::rebalance::RebalanceSettings;
+use server::rebalance::RebalanceStrategy;
+use server::rebalance::Rebalancer;
+use server::registration::RegistrationApi;
+use server::registration::RegistrationDiscovery;
+use server::registration::Registry;
+use server::selection::is_healthy;
+use server::selection::SharedAssignments;
+use server::selection::SliceSelection;
+use server::selection::UnhealthyOwner;
+use server::split::SplitSettings;
+use server::traffic::TrafficStats;
+use server::traffic::UsageSource;
+use server::upstream_tls::UpstreamTls;
+use server::upstream_tls::UpstreamTlsConfig;
+use server::usage_ingest::UsageIngest;
+use server::usage_ingest::WorkerTokens;
+use std::net::SocketAddr;
 use std::sync::Arc;
 use std::time::Duration;
+use std::time::Instant;
 
 pub fn main() {
     start_server();
@@ -66,21 +97,73 @@ fn start_server() {
     });
     server.bootstrap();
 
-    let db = tokio::runtime::Runtime::new()
+    let mut db = tokio::runtime::Runtime::new()
         .unwrap()
         .block_on(DB::new(false))
         .unwrap();
-    let dns_port = std::env::args()
-        .nth(2)
-        .expect("DNS Port number required")
-        .parse()
-        .unwrap();
+    db.damping = Damping {
+        grace_period: Duration::from_secs(env_or("SLICED_GRACE_PERIOD_SECS", 0)),
+        flap_window: Duration::from_secs(env_or("SLICED_FLAP_WINDOW_SECS", 300)),
+        max_flaps: env_or("SLICED_MAX_FLAPS", 0),
+        suppress_for: Duration::from_secs(env_or("SLICED_FLAP_SUPPRESS_SECS", 600)),
+    };
+    db.drain_rate = env_or("SLICED_DRAIN_SLICES_PER_CYCLE", 1);
+    db.churn_budget = ChurnBudget {
+        max_moves_per_minute: env_or("SLICED_MAX_MOVES_PER_MINUTE", 0),
+        max_in_flight: env_or("SLICED_MAX_IN_FLIGHT_MOVES", 0),
+        in_flight_for: Duration::from_secs(env_or("SLICED_MOVE_IN_FLIGHT_SECS", 30)),
+        max_server_fraction: env_or("SLICED_MAX_SERVER_MOVE_FRACTION", 0.0),
+    };
 
-    let discovery = Box::new(Discovery::new(dns_port, db));
+    db.assignments.policy = env_or("SLICED_UNHEALTHY_OWNER", UnhealthyOwner::FailFast);
+    let assignments = db.assignments.clone();
+    // Errors in proxied traffic mark servers unhealthy between active checks.
+    let passive = PassiveHealth::new(
+        db.states.clone(),
+        PassiveHealthSettings {
+            max_error_rate: env_or("SLICED_PASSIVE_MAX_ERROR_RATE", 0.0),
+            min_requests: env_or("SLICED_PASSIVE_MIN_REQUESTS", 10),
+            window: Duration::from_secs(env_or("SLICED_PASSIVE_WINDOW_SECS", 10)),
+        },
+    );
+    let (discovery, contributions) = discovery(&mut server, db.clone());
     let mut upstreams = LoadBalancer::from_backends(Backends::new(discovery));
 
-    // Configure HTTP health check
-    let hc = WorkerHealthCheck::new("sliced.local", false);
+    // Configure HTTP health check, from a JSON file given by
+    // SLICED_HEALTH_CHECK_CONFIG
+    let mut hc_config: HealthCheckConfig = match std::env::var("SLICED_HEALTH_CHECK_CONFIG") {
+        Ok(path) => serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap(),
+        Err(_) => HealthCheckConfig::default(),
+    };
+    let usage_port = std::env::var("SLICED_USAGE_PORT").ok();
+    if usage_port.is_some() {
+        // Pushed usage is the only usage, checks are just for liveness.
+        hc_config.collect_usage = false;
+    }
+    // TLS to workers, for both proxied requests and health checks, from a
+    // JSON file given by SLICED_UPSTREAM_TLS_CONFIG
+    let tls_config: UpstreamTlsConfig = match std::env::var("SLICED_UPSTREAM_TLS_CONFIG") {
+        Ok(path) => serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap(),
+        Err(_) => UpstreamTlsConfig::default(),
+    };
+    let tls = UpstreamTls::from_config(&tls_config).unwrap();
+
+    let usage_half_life = Duration::from_secs(env_or("SLICED_USAGE_HALF_LIFE_SECS", 0));
+    let mut hc = WorkerHealthCheck::from_config(&hc_config).unwrap();
+    hc.usage_half_life = usage_half_life;
+    if tls.is_enabled() {
+        hc.set_tls(&tls);
+    }
+
+    // Workers can push their usage instead, authenticating with their tokens
+    if let Some(port) = usage_port {
+        let mut ingest = UsageIngest::new(db.states.clone(), assignments.clone(), worker_tokens());
+        ingest.usage_half_life = usage_half_life;
+        ingest.max_body_bytes = hc_config.max_body_bytes;
+        let mut api = Service::new("usage".to_string(), ingest);
+        api.add_tcp(format!("0.0.0.0:{}", port).as_str());
+        server.add_service(api);
+    }
 
     upstreams.set_health_check(Box::new(hc));
     upstreams.health_check_frequency = Some(Duration::from_secs(1));
@@ -89,7 +172,72 @@ fn start_server() {
     let background = background_service("health check", upstreams);
 
     let upstreams = background.task();
-    let mut lb = pingora_proxy::http_proxy_service(&server.configuration, LB { upstreams });
+
+    // Slice load is what workers report, what the proxy sees, or both.
+    let usage = UsageSource {
+        source: env_or("SLICED_LOAD_SOURCE", "reported".to_string())
+            .parse()
+            .unwrap(),
+        traffic: TrafficStats::new(Duration::from_secs(env_or(
+            "SLICED_TRAFFIC_WINDOW_SECS",
+            60,
+        ))),
+        max_age: Duration::from_secs(env_or("SLICED_MAX_USAGE_AGE_SECS", 30)),
+    };
+
+    // The strategy used to rebalance this pool of workers, both by the
+    // rebalancer and when previewing a rebalance with the admin API.
+    let strategy = env_or("SLICED_REBALANCE_STRATEGY", "greedy".to_string());
+    let rebalance_interval = env_or("SLICED_REBALANCE_INTERVAL_SECS", 0);
+    if rebalance_interval > 0 {
+        let mut rebalancer = Rebalancer::new(
+            db.clone(),
+            upstreams.clone(),
+            rebalance_strategy(&strategy),
+            Duration::from_secs(rebalance_interval),
+        );
+        rebalancer.settings = RebalanceSettings {
+            start_above: env_or("SLICED_REBALANCE_START_IMBALANCE", 1.0),
+            sustain_for: Duration::from_secs(env_or("SLICED_REBALANCE_SUSTAIN_SECS", 0)),
+            stop_below: env_or("SLICED_REBALANCE_STOP_IMBALANCE", 1.0),
+            min_residence: Duration::from_secs(env_or("SLICED_MIN_SLICE_RESIDENCE_SECS", 0)),
+        };
+        rebalancer.usage = usage.clone();
+        rebalancer.split = SplitSettings {
+            max_slice_fraction: env_or("SLICED_SPLIT_SLICE_FRACTION", 0.0),
+            max_slices: env_or("SLICED_MAX_SLICES", 1000),
+            cooldown: Duration::from_secs(env_or("SLICED_SPLIT_COOLDOWN_SECS", 300)),
+            min_slice_fraction: env_or("SLICED_MERGE_SLICE_FRACTION", 0.0),
+            cold_for: Duration::from_secs(env_or("SLICED_MERGE_COLD_SECS", 600)),
+        };
+        server.add_service(background_service("rebalancer", rebalancer));
+    }
+    if let Ok(port) = std::env::var("SLICED_ADMIN_PORT") {
+        let mut api = AdminApi::new(
+            db,
+            upstreams.clone(),
+            rebalance_strategy(&strategy),
+            usage.clone(),
+        );
+        // Changes need SLICED_ADMIN_TOKEN when it's set, and the API is only
+        // reachable locally unless SLICED_ADMIN_ADDR says otherwise.
+        api.token = std::env::var("SLICED_ADMIN_TOKEN").ok();
+        api.contributions = contributions;
+        let addr = env_or("SLICED_ADMIN_ADDR", "127.0.0.1".to_string());
+        let mut admin = Service::new("admin".to_string(), api);
+        admin.add_tcp(format!("{}:{}", addr, port).as_str());
+        server.add_service(admin);
+    }
+    let mut lb = pingora_proxy::http_proxy_service(
+        &server.configuration,
+        LB {
+            upstreams,
+            assignments,
+            traffic: usage.traffic,
+            passive,
+            tls,
+        },
+    );
     lb.add_tcp(
         format!(
             "0.0.0.0:{}",
@@ -105,19 +253,156 @@ fn start_server() {
     server.run_forever();
 }
 
+/// Read a setting from the environment, falling back to `default` when it's
+/// not set.
+fn env_or<T: std::str::FromStr>(name: &str, default: T) -> T
+where
+    T::Err: std::fmt::Debug,
+{
+    std::env::var(name)
+        .map(|value| {
+            value
+                .parse()
+                .unwrap_or_else(|e| panic!("Invalid {}: {:?}", name, e))
+        })
+        .unwrap_or(default)
+}
+
+/// Look up the configured rebalance strategy. The optimizer is configured
+/// with a JSON file given by SLICED_OPTIMIZER_CONFIG.
+fn rebalance_strategy(name: &str) -> Box<dyn RebalanceStrategy> {
+    if name == "optimizer" {
+        if let Ok(path) = std::env::var("SLICED_OPTIMIZER_CONFIG") {
+            let optimizer: Optimizer =
+                serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap();
+            return Box::new(optimizer);
+        }
+    }
+    server::rebalance::strategy(name).expect("Unknown rebalance strategy")
+}
+
+/// Workers are either listed in a file, register themselves over HTTP, are
+/// listed in DNS, or some combination of those configured with
+/// SLICED_DISCOVERY_CONFIG, in which case which source each server came from
+/// is returned too.
+fn discovery(
+    server: &mut Server,
+    db: DB,
+) -> (
+    Box<dyn ServiceDiscovery + Send + Sync>,
+    Option<Contributions>,
+) {
+    if let Ok(path) = std::env::var("SLICED_DISCOVERY_CONFIG") {
+        let config: CompositeConfig =
+            serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap();
+        let sources = config
+            .sources
+            .into_iter()
+            .map(|config| {
+                let source: Box<dyn MembershipSource + Send + Sync> = match config.kind {
+                    SourceKind::Dns { port } => Box::new(Discovery::new(port, db.clone())),
+                    SourceKind::File { path } => {
+                        Box::new(FileDiscovery::new(path, db.clone()).unwrap())
+                    }
+                    SourceKind::Registration { port, ttl_secs } => {
+                        Box::new(registration_discovery(server, port, ttl_secs, db.clone()))
+                    }
+                };
+                Source {
+                    name: config.name,
+                    precedence: config.precedence,
+                    mode: config.mode,
+                    on_error: config.on_error,
+                    source,
+                }
+            })
+            .collect();
+        let composite = CompositeDiscovery::new(sources, db);
+        let contributions = composite.contributions();
+        return (Box::new(composite), Some(contributions));
+    }
+    let discovery: Box<dyn ServiceDiscovery + Send + Sync> =
+        if let Ok(path) = std::env::var("SLICED_WORKERS_FILE") {
+            Box::new(FileDiscovery::new(path, db).unwrap())
+        } else if let Ok(port) = std::env::var("SLICED_REGISTRATION_PORT") {
+            let ttl = env_or("SLICED_REGISTRATION_TTL_SECS", 5);
+            Box::new(registration_discovery(
+                server,
+                port.parse().unwrap(),
+                ttl,
+                db,
+            ))
+        } else {
+            let dns_port = std::env::args()
+                .nth(2)
+                .expect("DNS Port number required")
+                .parse()
+                .unwrap();
+            Box::new(Discovery::new(dns_port, db))
+        };
+    (discovery, None)
+}
+
+fn registration_discovery(
+    server: &mut Server,
+    port: u16,
+    ttl_secs: u64,
+    db: DB,
+) -> RegistrationDiscovery {
+    let registry = Registry::new(Duration::from_secs(ttl_secs));
+    let mut api = Service::new(
+        "registration".to_string(),
+        RegistrationApi::new(registry.clone(), worker_tokens()),
+    );
+    // Like the admin API, only reachable locally unless
+    // SLICED_REGISTRATION_ADDR says otherwise.
+    let addr = env_or("SLICED_REGISTRATION_ADDR", "127.0.0.1".to_string());
+    api.add_tcp(format!("{}:{}", addr, port).as_str());
+    server.add_service(api);
+    RegistrationDiscovery::new(registry, db)
+}
+
+/// Per-worker tokens, from the JSON file given by SLICED_USAGE_TOKENS, that
+/// workers authenticate with to push usage or register.
+fn worker_tokens() -> WorkerTokens {
+    let path = std::env::var("SLICED_USAGE_TOKENS").expect("SLICED_USAGE_TOKENS required");
+    serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap()
+}
+
 struct LB {
     upstreams: Arc<LoadBalancer<SliceSelection>>,
+    assignments: SharedAssignments,
+    traffic: TrafficStats,
+    passive: PassiveHealth,
+    tls: UpstreamTls,
 }
 
 impl LB {}
 
-struct Ctx {}
+struct Ctx {
+    /// The slice the request is for, once it's been sent upstream.
+    slice: Option<u16>,
+    /// The server the request was last sent to.
+    upstream: Option<SocketAddr>,
+    sent_at: Option<Instant>,
+    upstream_latency: Option<Duration>,
+    /// Whether the outcome of the last attempt has been passed to
+    /// [PassiveHealth], so a failure after the response header isn't
+    /// counted twice.
+    recorded: bool,
+}
 
 #[async_trait]
 impl ProxyHttp for LB {
     type CTX = Ctx;
     fn new_ctx(&self) -> Self::CTX {
-        Ctx {}
+        Ctx {
+            slice: None,
+            upstream: None,
+            sent_at: None,
+            upstream_latency: None,
+            recorded: false,
+        }
     }
 
     /// Define where the proxy should send the request to.
@@ -127,16 +412,113 @@ impl ProxyHttp for LB {
     async fn upstream_peer(
         &self,
         session: &mut Session,
-        _ctx: &mut Self::CTX,
+        ctx: &mut Self::CTX,
     ) -> Result<Box<HttpPeer>> {
-        let upstream = self
+        let key = session.get_header_bytes("X-User");
+        let Some(upstream) = self
             .upstreams
-            .select(session.get_header_bytes("X-User"), 256)
-            .or_err(pingora::HTTPStatus(502), "No upstreams available")?;
+            .select_with(key, 256, |backend, ready| ready && is_healthy(backend))
+        else {
+            // The slice has an owner, it's just not healthy.
+            if self.assignments.route(key).is_some() {
+                return Error::e_explain(pingora::HTTPStatus(503), "Slice owner unhealthy");
+            }
+            return Error::e_explain(pingora::HTTPStatus(502), "No upstreams available");
+        };
 
         info!("upstream peer is: {:?}", upstream);
 
-        let peer = Box::new(HttpPeer::new(upstream, false, "".to_string()));
+        // Retries go through here again but are still the one request.
+        if ctx.slice.is_none() {
+            ctx.slice = self.assignments.slice_for_key(key);
+            if let Some(slice) = ctx.slice {
+                self.traffic.start(slice);
+            }
+        }
+        ctx.upstream = upstream.addr.as_inet().copied();
+        ctx.sent_at = Some(Instant::now());
+        ctx.recorded = false;
+
+        let mut peer = Box::new(HttpPeer::new(upstream, false, "".to_string()));
+        self.tls.apply(&mut peer);
         Ok(peer)
     }
+
+    /// Tell the worker which slice the request is for, so it can report
+    /// usage for slices that have been split off.
+    async fn upstream_request_filter(
+        &self,
+        _session: &mut Session,
+        upstream_request: &mut RequestHeader,
+        ctx: &mut Self::CTX,
+    ) -> Result<()> {
+        if let Some(slice) = ctx.slice {
+            upstream_request.insert_header("X-Slice", slice.to_string())?;
+        }
+        Ok(())
+    }
+
+    fn upstream_response_filter(
+        &self,
+        _session: &mut Session,
+        upstream_response: &mut ResponseHeader,
+        ctx: &mut Self::CTX,
+    ) {
+        ctx.upstream_latency = ctx.sent_at.map(|sent_at| sent_at.elapsed());
+        if let Some(upstream) = ctx.upstream.filter(|_| !ctx.recorded) {
+            self.passive
+                .record(upstream, upstream_response.status.is_server_error());
+            ctx.recorded = true;
+        }
+    }
+
+    fn fail_to_connect(
+        &self,
+        _session: &mut Session,
+        peer: &HttpPeer,
+        ctx: &mut Self::CTX,
+        e: Box<Error>,
+    ) -> Box<Error> {
+        if let Some(upstream) = peer._address.as_inet().filter(|_| !ctx.recorded) {
+            self.passive.record(*upstream, true);
+            ctx.recorded = true;
+        }
+        e
+    }
+
+    /// Failures after connecting, including timeouts, count against the
+    /// server too.
+    fn error_while_proxy(
+        &self,
+        peer: &HttpPeer,
+        session: &mut Session,
+        e: Box<Error>,
+        ctx: &mut Self::CTX,
+        client_reused: bool,
+    ) -> Box<Error> {
+        if let Some(upstream) = peer._address.as_inet().filter(|_| !ctx.recorded) {
+            self.passive.record(*upstream, true);
+            ctx.recorded = true;
+        }
+        let mut e = e.more_context(format!("Peer: {}", peer));
+        e.retry
+            .decide_reuse(client_reused && !session.as_ref().retry_buffer_truncated());
+        e
+    }
+
+    async fn logging(&self, session: &mut Session, e: Option<&Error>, ctx: &mut Self::CTX) {
+        let Some(slice) = ctx.slice else {
+            return;
+        };
+        let server_error = session
+            .response_written()
+            .is_some_and(|response| response.status.is_server_error());
+        let bytes = session.body_bytes_read() + session.body_bytes_sent();
+        self.traffic.finish(
+            slice,
+            bytes,
+            e.is_some() || server_error,
+            ctx.upstream_latency,
+        );
+    }
 }
@@ -0,0 +1,247 @@
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+/// Settings for damping membership changes before they reach the slice
+/// assignments.
+#[derive(Debug, Clone)]
+pub struct Damping {
+    /// How long a server can be missing from discovery before its slices are
+    /// reassigned. A server that comes back within the grace period keeps its
+    /// slices.
+    pub grace_period: Duration,
+    /// Window over which appear/disappear transitions are counted.
+    pub flap_window: Duration,
+    /// Number of transitions within `flap_window` after which a server is
+    /// considered to be flapping. Zero disables flap detection.
+    pub max_flaps: usize,
+    /// How long a flapping server is kept out of the membership.
+    pub suppress_for: Duration,
+}
+
+impl Default for Damping {
+    fn default() -> Self {
+        Self {
+            grace_period: Duration::ZERO,
+            flap_window: Duration::from_secs(300),
+            max_flaps: 0,
+            suppress_for: Duration::from_secs(600),
+        }
+    }
+}
+
+/// Membership history, persisted alongside the slice assignments so that
+/// every load balancer damps changes the same way. Timestamps are
+/// milliseconds since the epoch.
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Membership {
+    /// Servers that still own slices but have dropped out of discovery, and
+    /// when they were first missed.
+    pub missing_since: BTreeMap<SocketAddr, i64>,
+    /// Recent appear/disappear transitions for each server.
+    pub transitions: BTreeMap<SocketAddr, Vec<i64>>,
+    /// Flapping servers that are being kept out, and until when.
+    pub suppressed_until: BTreeMap<SocketAddr, i64>,
+}
+
+impl Membership {
+    /// Work out which servers slices should be assigned to, given the current
+    /// servers and the ones discovery can see right now. Existing servers keep
+    /// their position in the list.
+    pub fn effective_servers(
+        &mut self,
+        current: &[SocketAddr],
+        observed: &BTreeSet<SocketAddr>,
+        now: i64,
+        damping: &Damping,
+    ) -> Vec<SocketAddr> {
+        let grace = damping.grace_period.as_millis() as i64;
+        let window = damping.flap_window.as_millis() as i64;
+        self.suppressed_until.retain(|_, until| *until > now);
+        for transitions in self.transitions.values_mut() {
+            transitions.retain(|t| now - t < window);
+        }
+        self.transitions
+            .retain(|_, transitions| !transitions.is_empty());
+
+        let mut servers = Vec::with_capacity(observed.len());
+        for server in current {
+            if observed.contains(server) {
+                if self.missing_since.remove(server).is_some() {
+                    // Coming back can be what makes it a flapping server.
+                    if self.record_transition(*server, now, damping) {
+                        continue;
+                    }
+                    println!("{} is back, keeping its slices", server);
+                }
+                servers.push(*server);
+                continue;
+            }
+            let missing_since = match self.missing_since.get(server) {
+                Some(missing_since) => *missing_since,
+                None => {
+                    println!(
+                        "{} is missing, holding its slices for {:?}",
+                        server, damping.grace_period
+                    );
+                    self.missing_since.insert(*server, now);
+                    self.record_transition(*server, now, damping);
+                    now
+                }
+            };
+            if now - missing_since < grace {
+                servers.push(*server);
+            } else {
+                self.missing_since.remove(server);
+            }
+        }
+        for server in observed {
+            if current.contains(server) {
+                continue;
+            }
+            if let Some(until) = self.suppressed_until.get(server) {
+                println!("{} is flapping, suppressed until {}", server, until);
+                continue;
+            }
+            if self.record_transition(*server, now, damping) {
+                continue;
+            }
+            servers.push(*server);
+        }
+        servers
+    }
+
+    /// Record that `server` appeared or disappeared. Returns whether that
+    /// makes it a flapping server, which is then suppressed.
+    fn record_transition(&mut self, server: SocketAddr, now: i64, damping: &Damping) -> bool {
+        let transitions = self.transitions.entry(server).or_default();
+        transitions.push(now);
+        if damping.max_flaps > 0 && transitions.len() >= damping.max_flaps {
+            println!(
+                "{} flapped {} times, suppressing",
+                server,
+                transitions.len()
+            );
+            self.suppressed_until
+                .insert(server, now + damping.suppress_for.as_millis() as i64);
+            return true;
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    fn damping() -> Damping {
+        Damping {
+            grace_period: Duration::from_secs(10),
+            flap_window: Duration::from_secs(60),
+            max_flaps: 3,
+            suppress_for: Duration::from_secs(120),
+        }
+    }
+
+    #[test]
+    fn test_grace_period() {
+        let mut membership = Membership::default();
+        let current = vec![addr(1), addr(2), addr(3)];
+        let observed = BTreeSet::from([addr(1), addr(3)]);
+
+        // Within the grace period the missing server keeps its place.
+        let servers = membership.effective_servers(&current, &observed, 0, &damping());
+        assert_eq!(servers, current);
+        let servers = membership.effective_servers(&current, &observed, 9_000, &damping());
+        assert_eq!(servers, current);
+
+        // After it the server is dropped.
+        let servers = membership.effective_servers(&current, &observed, 10_000, &damping());
+        assert_eq!(servers, vec![addr(1), addr(3)]);
+        assert!(membership.missing_since.is_empty());
+    }
+
+    #[test]
+    fn test_return_within_grace_period() {
+        let mut membership = Membership::default();
+        let current = vec![addr(1), addr(2)];
+        membership.effective_servers(&current, &BTreeSet::from([addr(1)]), 0, &damping());
+        let servers = membership.effective_servers(
+            &current,
+            &BTreeSet::from([addr(1), addr(2)]),
+            5_000,
+            &damping(),
+        );
+        assert_eq!(servers, current);
+        assert!(membership.missing_since.is_empty());
+        assert_eq!(membership.transitions[&addr(2)], vec![0, 5_000]);
+    }
+
+    #[test]
+    fn test_flapping_server_is_suppressed() {
+        let mut membership = Membership::default();
+        let damping = Damping {
+            grace_period: Duration::ZERO,
+            ..damping()
+        };
+        let all = BTreeSet::from([addr(1), addr(2)]);
+        let one = BTreeSet::from([addr(1)]);
+
+        let mut current = vec![addr(1), addr(2)];
+        // Leaves, comes back, leaves again: three transitions.
+        current = membership.effective_servers(&current, &one, 0, &damping);
+        assert_eq!(current, vec![addr(1)]);
+        current = membership.effective_servers(&current, &all, 1_000, &damping);
+        assert_eq!(current, vec![addr(1), addr(2)]);
+        current = membership.effective_servers(&current, &one, 2_000, &damping);
+        assert_eq!(current, vec![addr(1)]);
+        assert!(membership.suppressed_until.contains_key(&addr(2)));
+
+        // While suppressed it isn't let back in.
+        current = membership.effective_servers(&current, &all, 3_000, &damping);
+        assert_eq!(current, vec![addr(1)]);
+
+        // Once the suppression expires it is.
+        current = membership.effective_servers(&current, &all, 122_001, &damping);
+        assert_eq!(current, vec![addr(1), addr(2)]);
+    }
+
+    #[test]
+    fn test_flapping_server_is_not_let_back_in() {
+        let mut membership = Membership::default();
+        let damping = Damping {
+            grace_period: Duration::ZERO,
+            ..damping()
+        };
+        let all = BTreeSet::from([addr(1), addr(2)]);
+        let one = BTreeSet::from([addr(1)]);
+
+        // Appears, leaves, and the third transition is coming back.
+        let mut current = vec![addr(1)];
+        current = membership.effective_servers(&current, &all, 0, &damping);
+        assert_eq!(current, vec![addr(1), addr(2)]);
+        current = membership.effective_servers(&current, &one, 1_000, &damping);
+        assert_eq!(current, vec![addr(1)]);
+        current = membership.effective_servers(&current, &all, 2_000, &damping);
+        assert_eq!(current, vec![addr(1)]);
+        assert!(membership.suppressed_until.contains_key(&addr(2)));
+
+        // Likewise for a server coming back within its grace period.
+        let mut membership = Membership::default();
+        let grace = Damping {
+            grace_period: Duration::from_secs(10),
+            max_flaps: 2,
+            ..damping
+        };
+        let current = vec![addr(1), addr(2)];
+        let servers = membership.effective_servers(&current, &one, 0, &grace);
+        assert_eq!(servers, current);
+        let servers = membership.effective_servers(&current, &all, 1_000, &grace);
+        assert_eq!(servers, vec![addr(1)]);
+    }
+}
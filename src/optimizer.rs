@@ -0,0 +1,295 @@
+use crate::health_check::Usage;
+use crate::rebalance::RebalanceStrategy;
+use crate::slice_assignments::Balance;
+use crate::slice_assignments::Move;
+use crate::slice_assignments::SliceAssignments;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+const EPSILON: f64 = 1e-9;
+
+/// Finds a placement of slices that minimizes the highest load on any server,
+/// relative to its capacity, while spending at most `move_budget` on moves.
+/// Meant for occasional re-layouts rather than every cycle, eg:
+///
+/// ```json
+/// {"capacities": {"10.0.0.5:8000": 2.0}, "move_costs": {"12": 5.0}, "move_budget": 20.0}
+/// ```
+///
+/// This is a deterministic local search: each step makes the single move off
+/// the most loaded server that most improves the placement, until no move
+/// improves it or the budget runs out. When several servers share the highest
+/// load, moves that even out the rest of the servers still count as
+/// improvements so the search doesn't stall.
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default)]
+pub struct Optimizer {
+    /// Relative capacity of each server, servers that aren't listed have a
+    /// capacity of 1.
+    pub capacities: BTreeMap<SocketAddr, f64>,
+    /// The cost of moving each slice, eg. how much state it has to warm up.
+    pub move_costs: BTreeMap<u16, f64>,
+    /// The cost of moving slices that aren't listed in `move_costs`.
+    pub default_move_cost: f64,
+    /// The most the moves from a single run can cost in total.
+    pub move_budget: f64,
+}
+
+impl Default for Optimizer {
+    fn default() -> Self {
+        Self {
+            capacities: BTreeMap::new(),
+            move_costs: BTreeMap::new(),
+            default_move_cost: 1.0,
+            move_budget: 10.0,
+        }
+    }
+}
+
+/// Highest relative load, then the sum of squared relative loads.
+#[derive(Debug, Clone, Copy)]
+struct Objective(f64, f64);
+
+impl Objective {
+    fn better_than(&self, other: &Objective) -> bool {
+        self.0 < other.0 - EPSILON || (self.0 <= other.0 + EPSILON && self.1 < other.1 - EPSILON)
+    }
+}
+
+impl Optimizer {
+    fn capacity(&self, server: &SocketAddr) -> f64 {
+        self.capacities.get(server).copied().unwrap_or(1.0)
+    }
+
+    fn move_cost(&self, slice: u16) -> f64 {
+        self.move_costs
+            .get(&slice)
+            .copied()
+            .unwrap_or(self.default_move_cost)
+    }
+
+    fn relative_loads(&self, loads: &BTreeMap<SocketAddr, f64>) -> Vec<f64> {
+        loads
+            .iter()
+            .map(|(server, load)| load / self.capacity(server))
+            .collect()
+    }
+
+    fn objective(&self, loads: &BTreeMap<SocketAddr, f64>) -> Objective {
+        let relative = self.relative_loads(loads);
+        Objective(
+            relative.iter().copied().fold(0.0, f64::max),
+            relative.iter().map(|l| l * l).sum(),
+        )
+    }
+
+    /// Highest relative load over the mean, like [crate::slice_assignments::Balance].
+    fn imbalance(&self, loads: &BTreeMap<SocketAddr, f64>) -> f64 {
+        let relative = self.relative_loads(loads);
+        let mean = relative.iter().sum::<f64>() / relative.len() as f64;
+        if mean <= 0.0 {
+            return 1.0;
+        }
+        relative.iter().copied().fold(0.0, f64::max) / mean
+    }
+}
+
+impl RebalanceStrategy for Optimizer {
+    fn name(&self) -> &'static str {
+        "optimizer"
+    }
+
+    fn find_moves(
+        &self,
+        assignments: &SliceAssignments,
+        usage: &HashMap<SocketAddr, Usage>,
+    ) -> Vec<Move> {
+        // Only servers that report usage take part, like with Balance.
+        let mut loads: BTreeMap<SocketAddr, f64> = BTreeMap::new();
+        let mut owners: BTreeMap<u16, SocketAddr> = BTreeMap::new();
+        let mut slice_loads: BTreeMap<u16, f64> = BTreeMap::new();
+        let (servers, server_slices) = Balance::usage_stats(usage);
+        for (server, load) in servers {
+            loads.insert(server, load as f64);
+        }
+        for (&server, slices) in &server_slices {
+            for (&slice, &load) in slices {
+                owners.insert(slice, server);
+                slice_loads.insert(slice, load as f64);
+            }
+        }
+        let original = owners.clone();
+        let mut placement = assignments.clone();
+        let mut benefits: BTreeMap<u16, f64> = BTreeMap::new();
+        let mut spent = 0.0;
+
+        loop {
+            let current = self.objective(&loads);
+            let Some(hot) = loads
+                .iter()
+                .map(|(server, load)| (*server, load / self.capacity(server)))
+                .fold(
+                    None,
+                    |hottest: Option<(SocketAddr, f64)>, (server, load)| match hottest {
+                        Some((_, max)) if max >= load => hottest,
+                        _ => Some((server, load)),
+                    },
+                )
+                .map(|(server, _)| server)
+            else {
+                break;
+            };
+
+            // (slice, target, objective, cost)
+            let mut best: Option<(u16, SocketAddr, Objective, f64)> = None;
+            for (&slice, _) in owners.iter().filter(|(_, owner)| **owner == hot) {
+                let load = slice_loads[&slice];
+                for &target in loads.keys() {
+                    if target == hot
+                        || !(placement.can_take(slice, &target) || original[&slice] == target)
+                    {
+                        continue;
+                    }
+                    let cost = if original[&slice] == hot {
+                        self.move_cost(slice)
+                    } else if original[&slice] == target {
+                        -self.move_cost(slice)
+                    } else {
+                        0.0
+                    };
+                    if spent + cost > self.move_budget + EPSILON {
+                        continue;
+                    }
+                    let mut after = loads.clone();
+                    *after.get_mut(&hot).unwrap() -= load;
+                    *after.get_mut(&target).unwrap() += load;
+                    let objective = self.objective(&after);
+                    if !objective.better_than(&current) {
+                        continue;
+                    }
+                    let better = match &best {
+                        None => true,
+                        Some((_, _, best_objective, best_cost)) => {
+                            objective.better_than(best_objective)
+                                || (!best_objective.better_than(&objective) && cost < *best_cost)
+                        }
+                    };
+                    if better {
+                        best = Some((slice, target, objective, cost));
+                    }
+                }
+            }
+
+            let Some((slice, target, _, cost)) = best else {
+                break;
+            };
+            let before = self.imbalance(&loads);
+            let load = slice_loads[&slice];
+            *loads.get_mut(&hot).unwrap() -= load;
+            *loads.get_mut(&target).unwrap() += load;
+            owners.insert(slice, target);
+            let _ = placement.move_slice(slice, target);
+            spent += cost;
+            *benefits.entry(slice).or_default() += before - self.imbalance(&loads);
+        }
+
+        owners
+            .iter()
+            .filter(|(slice, owner)| original[slice] != **owner)
+            .map(|(&slice_id, &to_server)| Move {
+                slice_id,
+                from_server: original[&slice_id],
+                to_server,
+                benefit: benefits[&slice_id] as f32,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::health_check::SliceUsage;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    fn setup(hot: &[u32]) -> (SliceAssignments, HashMap<SocketAddr, Usage>) {
+        let assignments = SliceAssignments::new(vec![addr(1), addr(2)]);
+        let usage = HashMap::from([
+            (
+                addr(1),
+                Usage {
+                    slices: hot
+                        .iter()
+                        .enumerate()
+                        .map(|(slice, &load)| {
+                            (
+                                slice as u16,
+                                SliceUsage {
+                                    load,
+                                    ..Default::default()
+                                },
+                            )
+                        })
+                        .collect(),
+                    ..Default::default()
+                },
+            ),
+            (addr(2), Usage::default()),
+        ]);
+        (assignments, usage)
+    }
+
+    fn server_loads(hot: &[u32], moves: &[Move]) -> (u32, u32) {
+        let moved: u32 = moves.iter().map(|m| hot[m.slice_id as usize]).sum();
+        (hot.iter().sum::<u32>() - moved, moved)
+    }
+
+    #[test]
+    fn test_minimizes_max_load() {
+        let hot = [50, 40, 30, 20, 10];
+        let (assignments, usage) = setup(&hot);
+        let moves = Optimizer::default().find_moves(&assignments, &usage);
+        // An even 75/75 split isn't possible with these slices.
+        let (a, b) = server_loads(&hot, &moves);
+        assert_eq!(a.max(b), 80);
+        assert!(moves.iter().all(|m| m.to_server == addr(2)));
+    }
+
+    #[test]
+    fn test_move_budget_and_costs() {
+        let hot = [50, 40, 30, 20, 10];
+        let (assignments, usage) = setup(&hot);
+        let optimizer = Optimizer {
+            move_budget: 1.0,
+            ..Default::default()
+        };
+        let moves = optimizer.find_moves(&assignments, &usage);
+        assert_eq!(moves.len(), 1);
+        assert_eq!(moves[0].slice_id, 0);
+
+        // The biggest slice is too expensive to move.
+        let optimizer = Optimizer {
+            move_costs: BTreeMap::from([(0, 100.0)]),
+            ..Default::default()
+        };
+        let moves = optimizer.find_moves(&assignments, &usage);
+        assert!(moves.iter().all(|m| m.slice_id != 0));
+        assert_eq!(server_loads(&hot, &moves), (80, 70));
+    }
+
+    #[test]
+    fn test_capacities() {
+        let hot = [30, 30, 30, 30, 30, 30];
+        let (assignments, usage) = setup(&hot);
+        let optimizer = Optimizer {
+            capacities: BTreeMap::from([(addr(1), 2.0)]),
+            ..Default::default()
+        };
+        let moves = optimizer.find_moves(&assignments, &usage);
+        assert_eq!(server_loads(&hot, &moves), (120, 60));
+    }
+}
@@ -0,0 +1,155 @@
+use crate::health_check::ServerStates;
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+/// When proxied traffic marks a server unhealthy.
+#[derive(Debug, Clone)]
+pub struct PassiveHealthSettings {
+    /// The fraction of requests in the window that have to fail. Zero turns
+    /// passive health checking off.
+    pub max_error_rate: f64,
+    /// Fewer requests than this in the window are never enough to go on.
+    pub min_requests: u32,
+    pub window: Duration,
+}
+
+impl Default for PassiveHealthSettings {
+    fn default() -> Self {
+        Self {
+            max_error_rate: 0.0,
+            min_requests: 10,
+            window: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Requests and failures in one second.
+#[derive(Debug, Default)]
+struct Bucket {
+    second: u64,
+    requests: u32,
+    failures: u32,
+}
+
+/// Marks servers unhealthy when too many proxied requests to them fail to
+/// connect, time out or get a 5xx, without waiting for the next active health
+/// check. Only the active health check marks them healthy again, after its
+/// usual number of successes in a row.
+#[derive(Clone)]
+pub struct PassiveHealth {
+    states: ServerStates,
+    settings: PassiveHealthSettings,
+    outcomes: Arc<Mutex<HashMap<SocketAddr, VecDeque<Bucket>>>>,
+    epoch: Instant,
+}
+
+impl PassiveHealth {
+    pub fn new(states: ServerStates, settings: PassiveHealthSettings) -> Self {
+        Self {
+            states,
+            settings,
+            outcomes: Arc::new(Mutex::new(HashMap::new())),
+            epoch: Instant::now(),
+        }
+    }
+
+    /// Record how a request to `server` went.
+    pub fn record(&self, server: SocketAddr, failed: bool) {
+        self.record_at(server, failed, Instant::now());
+    }
+
+    fn record_at(&self, server: SocketAddr, failed: bool, now: Instant) {
+        if self.settings.max_error_rate <= 0.0 {
+            return;
+        }
+        // Servers that are already unhealthy wait for the active check.
+        let Some(status) = self.states.get(&server) else {
+            return;
+        };
+        if !status.inner.read().unwrap().is_healthy {
+            return;
+        }
+
+        let second = now.duration_since(self.epoch).as_secs();
+        let window = self.settings.window.as_secs().max(1);
+        let mut outcomes = self.outcomes.lock().unwrap();
+        let buckets = outcomes.entry(server).or_default();
+        while buckets.front().is_some_and(|b| b.second + window <= second) {
+            buckets.pop_front();
+        }
+        if buckets.back().map(|b| b.second) != Some(second) {
+            buckets.push_back(Bucket {
+                second,
+                ..Default::default()
+            });
+        }
+        let bucket = buckets.back_mut().unwrap();
+        bucket.requests += 1;
+        bucket.failures += failed as u32;
+
+        let requests: u32 = buckets.iter().map(|b| b.requests).sum();
+        let failures: u32 = buckets.iter().map(|b| b.failures).sum();
+        let error_rate = failures as f64 / requests as f64;
+        if requests >= self.settings.min_requests && error_rate >= self.settings.max_error_rate {
+            println!(
+                "marking {} unhealthy, {} of the last {} requests failed",
+                server, failures, requests
+            );
+            let mut state = status.inner.write().unwrap();
+            state.is_healthy = false;
+            state.consecutive_successes = 0;
+            // Start afresh once the active check brings it back.
+            outcomes.remove(&server);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_rate() {
+        let states = ServerStates::default();
+        let server: SocketAddr = "127.0.0.1:8000".parse().unwrap();
+        let status = states.status(server);
+        let passive = PassiveHealth::new(
+            states,
+            PassiveHealthSettings {
+                max_error_rate: 0.5,
+                min_requests: 4,
+                window: Duration::from_secs(10),
+            },
+        );
+        let at = |secs| passive.epoch + Duration::from_secs(secs);
+        let healthy = || status.inner.read().unwrap().is_healthy;
+
+        // Too few requests to go on.
+        passive.record_at(server, true, at(0));
+        passive.record_at(server, true, at(0));
+        assert!(healthy());
+        // Failures that have dropped out of the window don't count.
+        passive.record_at(server, false, at(20));
+        passive.record_at(server, true, at(20));
+        passive.record_at(server, false, at(20));
+        passive.record_at(server, false, at(21));
+        assert!(healthy());
+        passive.record_at(server, true, at(21));
+        passive.record_at(server, true, at(22));
+        assert!(!healthy());
+
+        // It stays down until enough active checks in a row say otherwise.
+        passive.record_at(server, false, at(23));
+        assert!(!healthy());
+        status.inner.write().unwrap().record_check(true, 2);
+        assert!(!healthy());
+        status.inner.write().unwrap().record_check(true, 2);
+        passive.record_at(server, true, at(24));
+        assert!(healthy());
+    }
+}
@@ -0,0 +1,267 @@
+use crate::health_check::Usage;
+use crate::rebalance::RebalanceStrategy;
+use crate::slice_assignments::SliceAssignments;
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
+
+/// A slice that would move if a plan were applied.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct PlannedMove {
+    pub slice: u16,
+    pub from: SocketAddr,
+    pub to: SocketAddr,
+    /// The load that moves with the slice, if `from` reported usage for it.
+    #[serde(default)]
+    pub load_delta: Option<u32>,
+    /// The improvement in imbalance the strategy expects from the move, only set
+    /// for rebalancing moves.
+    #[serde(default)]
+    pub benefit: Option<f32>,
+}
+
+/// A set of moves worked out against a specific version of the assignments.
+/// Plans are only ever computed, applying one is a separate step that checks
+/// the assignments haven't changed since, see [crate::db::DB::apply_plan].
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Plan {
+    pub version: i64,
+    pub moves: Vec<PlannedMove>,
+}
+
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} slice move(s) against version {}",
+            self.moves.len(),
+            self.version
+        )?;
+        for mov in &self.moves {
+            write!(f, "  slice {}: {} -> {}", mov.slice, mov.from, mov.to)?;
+            if let Some(load) = mov.load_delta {
+                write!(f, ", load {}", load)?;
+            }
+            if let Some(benefit) = mov.benefit {
+                write!(f, ", benefit {:.3}", benefit)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+fn slice_load(usage: &HashMap<SocketAddr, Usage>, server: &SocketAddr, slice: u16) -> Option<u32> {
+    Some(usage.get(server)?.slices.get(&slice)?.load)
+}
+
+/// The moves that would happen if the servers changed to `servers`. Servers
+/// that stay keep their slices, new servers are added at the end, and grace
+/// periods or flap damping aren't applied.
+pub fn plan_membership(
+    assignments: &SliceAssignments,
+    version: i64,
+    servers: &BTreeSet<SocketAddr>,
+    usage: &HashMap<SocketAddr, Usage>,
+) -> Plan {
+    let mut proposed = assignments.clone();
+    let mut new_servers: Vec<_> = assignments
+        .servers
+        .iter()
+        .filter(|s| servers.contains(s))
+        .copied()
+        .collect();
+    new_servers.extend(servers.iter().filter(|s| !assignments.servers.contains(s)));
+    proposed.update(new_servers);
+
+    let moves = assignments
+        .slices()
+        .filter_map(|slice| {
+            let from = assignments.owner(slice)?;
+            let to = proposed.owner(slice)?;
+            (from != to).then(|| PlannedMove {
+                slice,
+                from,
+                to,
+                load_delta: slice_load(usage, &from, slice),
+                benefit: None,
+            })
+        })
+        .collect();
+    Plan { version, moves }
+}
+
+/// The moves `strategy` would make given each server's reported `usage`.
+/// Usage for slices a server no longer owns is ignored.
+pub fn plan_rebalance(
+    assignments: &SliceAssignments,
+    version: i64,
+    usage: &HashMap<SocketAddr, Usage>,
+    strategy: &dyn RebalanceStrategy,
+) -> Plan {
+    let usage: HashMap<_, _> = usage
+        .iter()
+        .filter(|(server, _)| assignments.servers.contains(server))
+        .map(|(&server, usage)| {
+            let mut usage = usage.clone();
+            usage
+                .slices
+                .retain(|&slice, _| assignments.owner(slice) == Some(server));
+            (server, usage)
+        })
+        .collect();
+    let moves = strategy
+        .find_moves(assignments, &usage)
+        .into_iter()
+        .map(|mov| PlannedMove {
+            slice: mov.slice_id,
+            from: mov.from_server,
+            to: mov.to_server,
+            load_delta: slice_load(&usage, &mov.from_server, mov.slice_id),
+            benefit: Some(mov.benefit),
+        })
+        .collect();
+    Plan { version, moves }
+}
+
+#[derive(Debug)]
+pub enum ApplyError {
+    /// The assignments changed after the plan was made, it needs redoing.
+    VersionChanged {
+        planned: i64,
+        current: i64,
+    },
+    /// A move doesn't make sense against the current assignments.
+    InvalidMove(String),
+    Db(libsql::Error),
+}
+
+impl fmt::Display for ApplyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApplyError::VersionChanged { planned, current } => write!(
+                f,
+                "plan was made against version {} but the assignments are at version {}",
+                planned, current
+            ),
+            ApplyError::InvalidMove(e) => write!(f, "invalid move: {}", e),
+            ApplyError::Db(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<libsql::Error> for ApplyError {
+    fn from(e: libsql::Error) -> Self {
+        ApplyError::Db(e)
+    }
+}
+
+/// Make the moves in `plan` to `assignments`.
+pub fn apply_moves(assignments: &mut SliceAssignments, plan: &Plan) -> Result<(), ApplyError> {
+    for mov in &plan.moves {
+        if assignments.owner(mov.slice) != Some(mov.from) {
+            return Err(ApplyError::InvalidMove(format!(
+                "slice {} isn't owned by {}",
+                mov.slice, mov.from
+            )));
+        }
+        assignments
+            .move_slice(mov.slice, mov.to)
+            .map_err(ApplyError::InvalidMove)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::health_check::SliceUsage;
+    use crate::slice_assignments::NUM_SLICES;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    fn usage(slices: &[(u16, u32)]) -> Usage {
+        Usage {
+            slices: slices
+                .iter()
+                .map(|&(slice, load)| {
+                    (
+                        slice,
+                        SliceUsage {
+                            load,
+                            ..Default::default()
+                        },
+                    )
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_plan_membership() {
+        let assignments = SliceAssignments::new(vec![addr(1), addr(2), addr(3)]);
+        let removed: Vec<_> = (0..NUM_SLICES)
+            .filter(|&s| assignments.owner(s) == Some(addr(2)))
+            .collect();
+        let usage = HashMap::from([(addr(2), usage(&[(removed[0], 40)]))]);
+
+        let plan = plan_membership(&assignments, 7, &BTreeSet::from([addr(1), addr(3)]), &usage);
+        assert_eq!(plan.version, 7);
+        assert_eq!(
+            plan.moves.iter().map(|m| m.slice).collect::<Vec<_>>(),
+            removed
+        );
+        assert!(plan
+            .moves
+            .iter()
+            .all(|m| m.from == addr(2) && m.to != addr(2)));
+        assert_eq!(plan.moves[0].load_delta, Some(40));
+        assert!(plan.to_string().contains(", load 40"));
+
+        // Planning doesn't touch the assignments.
+        assert_eq!(assignments.slice_count(&addr(2)), removed.len());
+    }
+
+    #[test]
+    fn test_plan_rebalance_and_apply() {
+        let mut assignments = SliceAssignments::new(vec![addr(1), addr(2)]);
+        let owned = |server| {
+            (0..NUM_SLICES)
+                .filter(|&s| assignments.owner(s) == Some(server))
+                .collect::<Vec<_>>()
+        };
+        let (hot, cold) = (owned(addr(1)), owned(addr(2)));
+        let usage = HashMap::from([
+            (
+                addr(1),
+                usage(&[(hot[0], 50), (hot[1], 40), (cold[0], 500)]),
+            ),
+            (addr(2), usage(&[(cold[0], 10)])),
+        ]);
+
+        let plan = plan_rebalance(&assignments, 1, &usage, &crate::rebalance::Greedy);
+        assert_eq!(
+            plan.moves,
+            vec![PlannedMove {
+                slice: hot[1],
+                from: addr(1),
+                to: addr(2),
+                load_delta: Some(40),
+                benefit: plan.moves[0].benefit,
+            }]
+        );
+        assert!(plan.moves[0].benefit.unwrap() > 0.0);
+
+        apply_moves(&mut assignments, &plan).unwrap();
+        assert_eq!(assignments.owner(hot[1]), Some(addr(2)));
+        // The slice has already moved.
+        assert!(matches!(
+            apply_moves(&mut assignments, &plan),
+            Err(ApplyError::InvalidMove(_))
+        ));
+    }
+}
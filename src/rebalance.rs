@@ -0,0 +1,344 @@
+use crate::db::new_timestamp;
+use crate::db::DB;
+use crate::health_check::Usage;
+use crate::optimizer::Optimizer;
+use crate::planner::plan_rebalance;
+use crate::selection::SliceSelection;
+use crate::slice_assignments::Balance;
+use crate::slice_assignments::Move;
+use crate::slice_assignments::SliceAssignments;
+use crate::split::hot_slices;
+use crate::split::ColdSlices;
+use crate::split::SplitSettings;
+use crate::traffic::UsageSource;
+use async_trait::async_trait;
+use pingora_core::server::ShutdownWatch;
+use pingora_core::services::background::BackgroundService;
+use pingora_load_balancing::LoadBalancer;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+/// An algorithm for deciding which slices to move to even out load.
+pub trait RebalanceStrategy: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// The moves to make given the current assignments and the usage each
+    /// server reported for the slices it owns. Moves must respect
+    /// [SliceAssignments::can_take].
+    fn find_moves(
+        &self,
+        assignments: &SliceAssignments,
+        usage: &HashMap<SocketAddr, Usage>,
+    ) -> Vec<Move>;
+}
+
+/// Repeatedly takes whichever move of a slice off an overloaded server, or
+/// swap with a smaller slice elsewhere, most reduces the imbalance, see
+/// [Balance].
+pub struct Greedy;
+
+impl RebalanceStrategy for Greedy {
+    fn name(&self) -> &'static str {
+        "greedy"
+    }
+
+    fn find_moves(
+        &self,
+        assignments: &SliceAssignments,
+        usage: &HashMap<SocketAddr, Usage>,
+    ) -> Vec<Move> {
+        let (servers, server_slices) = Balance::usage_stats(usage);
+        Balance::find_moves(servers, &server_slices, assignments)
+    }
+}
+
+/// Look up a strategy by the name used in configuration.
+pub fn strategy(name: &str) -> Option<Box<dyn RebalanceStrategy>> {
+    match name {
+        "greedy" => Some(Box::new(Greedy)),
+        "optimizer" => Some(Box::new(Optimizer::default())),
+        _ => None,
+    }
+}
+
+/// When the rebalancer acts on an imbalance, the highest server load over the
+/// mean load.
+#[derive(Debug, Clone)]
+pub struct RebalanceSettings {
+    /// Rebalancing starts once the imbalance has been above `start_above` for
+    /// `sustain_for`...
+    pub start_above: f32,
+    pub sustain_for: Duration,
+    /// ...and carries on until it drops to `stop_below`.
+    pub stop_below: f32,
+    /// How long a slice stays where it is after it moves, so it doesn't
+    /// bounce straight back.
+    pub min_residence: Duration,
+}
+
+impl Default for RebalanceSettings {
+    fn default() -> Self {
+        Self {
+            start_above: 1.0,
+            sustain_for: Duration::ZERO,
+            stop_below: 1.0,
+            min_residence: Duration::ZERO,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Trigger {
+    active: bool,
+    above_since: Option<Instant>,
+}
+
+impl Trigger {
+    /// Whether to rebalance given the current imbalance.
+    fn update(&mut self, imbalance: f32, now: Instant, settings: &RebalanceSettings) -> bool {
+        if self.active {
+            if imbalance <= settings.stop_below {
+                println!("imbalance down to {:.3}, rebalancing stopped", imbalance);
+                self.active = false;
+            }
+        } else if imbalance > settings.start_above {
+            let above_since = *self.above_since.get_or_insert(now);
+            if now.duration_since(above_since) >= settings.sustain_for {
+                println!("imbalance at {:.3}, rebalancing started", imbalance);
+                self.active = true;
+            }
+        }
+        if imbalance <= settings.start_above {
+            self.above_since = None;
+        }
+        self.active
+    }
+}
+
+/// Pin slices that moved within `min_residence` to where they are.
+fn hold_recent_moves(
+    assignments: &SliceAssignments,
+    now: i64,
+    min_residence: Duration,
+) -> SliceAssignments {
+    let mut held = assignments.clone();
+    for (&slice, &moved_at) in &assignments.churn.last_moved {
+        if now - moved_at < min_residence.as_millis() as i64 {
+            if let Some(owner) = assignments.owner(slice) {
+                held.constraints.pins.insert(slice, owner);
+            }
+        }
+    }
+    held
+}
+
+/// Background service that periodically moves slices around based on the
+/// usage workers report to the health check, or the traffic the proxy sees,
+/// see [UsageSource]. Moves go through the same path as an applied plan so
+/// they're subject to the churn budget. Slices too hot to be helped by moving
+/// them are split first, and slices that stay cold are merged.
+pub struct Rebalancer {
+    db: DB,
+    upstreams: Arc<LoadBalancer<SliceSelection>>,
+    strategy: Box<dyn RebalanceStrategy>,
+    interval: Duration,
+    pub settings: RebalanceSettings,
+    pub usage: UsageSource,
+    pub split: SplitSettings,
+    trigger: Mutex<Trigger>,
+    cold: Mutex<ColdSlices>,
+}
+
+impl Rebalancer {
+    pub fn new(
+        db: DB,
+        upstreams: Arc<LoadBalancer<SliceSelection>>,
+        strategy: Box<dyn RebalanceStrategy>,
+        interval: Duration,
+    ) -> Self {
+        Self {
+            db,
+            upstreams,
+            strategy,
+            interval,
+            settings: RebalanceSettings::default(),
+            usage: UsageSource::default(),
+            split: SplitSettings::default(),
+            trigger: Mutex::new(Trigger::default()),
+            cold: Mutex::new(ColdSlices::default()),
+        }
+    }
+
+    async fn rebalance(&self) -> Result<(), String> {
+        let (assignments, version) = self
+            .db
+            .versioned_assignments()
+            .await
+            .map_err(|e| e.to_string())?;
+        let usage = self.usage.usage(&self.upstreams, &assignments);
+        let (servers, _) = Balance::usage_stats(&usage);
+        if servers.is_empty() {
+            return Ok(());
+        }
+        // Split slices first, the halves get moved apart in later rounds once
+        // they report load of their own.
+        let hot = hot_slices(&assignments, &usage, new_timestamp(), &self.split);
+        if !hot.is_empty() {
+            return self
+                .db
+                .split_slices(&hot)
+                .await
+                .map(|_| ())
+                .map_err(|e| e.to_string());
+        }
+        let merges =
+            self.cold
+                .lock()
+                .unwrap()
+                .merges(&assignments, &usage, new_timestamp(), &self.split);
+        if !merges.is_empty() {
+            return self
+                .db
+                .merge_slices(&merges)
+                .await
+                .map(|_| ())
+                .map_err(|e| e.to_string());
+        }
+        let imbalance = Balance::calculate_imbalance(&servers);
+        let act = self
+            .trigger
+            .lock()
+            .unwrap()
+            .update(imbalance, Instant::now(), &self.settings);
+        if !act {
+            return Ok(());
+        }
+        let held = hold_recent_moves(&assignments, new_timestamp(), self.settings.min_residence);
+        let plan = plan_rebalance(&held, version, &usage, self.strategy.as_ref());
+        if plan.moves.is_empty() {
+            return Ok(());
+        }
+        println!("rebalancing with {}: {}", self.strategy.name(), plan);
+        self.db
+            .apply_plan(&plan)
+            .await
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+}
+
+#[async_trait]
+impl BackgroundService for Rebalancer {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        let mut interval = tokio::time::interval(self.interval);
+        loop {
+            tokio::select! {
+                _ = shutdown.changed() => return,
+                _ = interval.tick() => {}
+            }
+            if let Err(e) = self.rebalance().await {
+                println!("rebalancing failed: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::health_check::SliceUsage;
+    use crate::slice_assignments::NUM_SLICES;
+
+    #[test]
+    fn test_greedy_strategy() {
+        let servers: Vec<SocketAddr> = vec![
+            "127.0.0.1:8001".parse().unwrap(),
+            "127.0.0.1:8002".parse().unwrap(),
+        ];
+        let assignments = SliceAssignments::new(servers.clone());
+        let hot: Vec<_> = (0..NUM_SLICES)
+            .filter(|&s| assignments.owner(s) == Some(servers[0]))
+            .take(2)
+            .collect();
+        let usage = HashMap::from([
+            (
+                servers[0],
+                Usage {
+                    slices: HashMap::from([
+                        (
+                            hot[0],
+                            SliceUsage {
+                                load: 300,
+                                ..Default::default()
+                            },
+                        ),
+                        (
+                            hot[1],
+                            SliceUsage {
+                                load: 200,
+                                ..Default::default()
+                            },
+                        ),
+                    ]),
+                    ..Default::default()
+                },
+            ),
+            (servers[1], Usage::default()),
+        ]);
+
+        let greedy = strategy("greedy").unwrap();
+        assert_eq!(greedy.name(), "greedy");
+        let moves = greedy.find_moves(&assignments, &usage);
+        assert_eq!(moves.len(), 1);
+        assert_eq!(moves[0].slice_id, hot[0]);
+        assert!(strategy("unknown").is_none());
+    }
+
+    #[test]
+    fn test_hysteresis() {
+        let settings = RebalanceSettings {
+            start_above: 1.5,
+            sustain_for: Duration::from_secs(10),
+            stop_below: 1.1,
+            min_residence: Duration::ZERO,
+        };
+        let mut trigger = Trigger::default();
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        // A short spike doesn't start rebalancing.
+        assert!(!trigger.update(2.0, at(0), &settings));
+        assert!(!trigger.update(1.2, at(5), &settings));
+        assert!(!trigger.update(2.0, at(6), &settings));
+        // A sustained one does.
+        assert!(trigger.update(2.0, at(16), &settings));
+        // It carries on through the band, then stops below it.
+        assert!(trigger.update(1.3, at(17), &settings));
+        assert!(!trigger.update(1.1, at(18), &settings));
+        assert!(!trigger.update(1.3, at(19), &settings));
+    }
+
+    #[test]
+    fn test_hold_recent_moves() {
+        let servers: Vec<SocketAddr> = vec![
+            "127.0.0.1:8001".parse().unwrap(),
+            "127.0.0.1:8002".parse().unwrap(),
+        ];
+        let mut assignments = SliceAssignments::new(servers.clone());
+        assignments.churn.last_moved.insert(0, 1_000);
+        assignments.churn.last_moved.insert(1, 50_000);
+
+        let held = hold_recent_moves(&assignments, 60_000, Duration::from_secs(30));
+        assert_eq!(
+            held.constraints.pins.keys().copied().collect::<Vec<_>>(),
+            vec![1]
+        );
+        let owner = assignments.owner(1).unwrap();
+        let other = servers.iter().find(|&&s| s != owner).unwrap();
+        assert!(!held.can_take(1, other));
+    }
+}
@@ -0,0 +1,291 @@
+use crate::api::json_response;
+use crate::api::read_json;
+use crate::api::same_token;
+use crate::db::DB;
+use crate::discovery::assign_servers;
+use crate::discovery::MembershipSource;
+use crate::usage_ingest::WorkerTokens;
+use async_trait::async_trait;
+use http::Method;
+use http::Response;
+use http::StatusCode;
+use pingora_core::apps::http_app::ServeHttp;
+use pingora_core::protocols::http::ServerSession;
+use pingora_error::Result;
+use pingora_load_balancing::discovery::ServiceDiscovery;
+use pingora_load_balancing::Backend;
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+/// What a worker announces about itself when it registers.
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Registration {
+    pub address: SocketAddr,
+    #[serde(default)]
+    pub capacity: Option<u32>,
+    #[serde(default)]
+    pub version: Option<String>,
+}
+
+#[derive(Clone, Debug)]
+struct Worker {
+    registration: Registration,
+    last_heartbeat: Instant,
+}
+
+/// Registry holds the workers that have registered themselves. A worker is a
+/// member until it deregisters or goes `ttl` without a heartbeat.
+#[derive(Clone)]
+pub struct Registry {
+    ttl: Duration,
+    workers: Arc<RwLock<HashMap<SocketAddr, Worker>>>,
+}
+
+impl Registry {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            workers: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    /// Register a worker, or refresh the details of an existing one. Counts as
+    /// a heartbeat.
+    pub fn register(&self, registration: Registration) {
+        let mut workers = self.workers.write().unwrap();
+        workers.insert(
+            registration.address,
+            Worker {
+                registration,
+                last_heartbeat: Instant::now(),
+            },
+        );
+    }
+
+    /// Record a heartbeat. Returns false if the worker isn't registered (or
+    /// has already expired) and needs to register again.
+    pub fn heartbeat(&self, address: &SocketAddr) -> bool {
+        self.expire(Instant::now());
+        match self.workers.write().unwrap().get_mut(address) {
+            Some(worker) => {
+                worker.last_heartbeat = Instant::now();
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn deregister(&self, address: &SocketAddr) -> bool {
+        self.workers.write().unwrap().remove(address).is_some()
+    }
+
+    /// Workers that have heartbeat within the ttl.
+    pub fn live_workers(&self) -> Vec<Registration> {
+        self.expire(Instant::now());
+        let mut workers: Vec<_> = self
+            .workers
+            .read()
+            .unwrap()
+            .values()
+            .map(|w| w.registration.clone())
+            .collect();
+        workers.sort_by_key(|w| w.address);
+        workers
+    }
+
+    fn expire(&self, now: Instant) {
+        self.workers.write().unwrap().retain(|address, worker| {
+            let alive = now.saturating_duration_since(worker.last_heartbeat) < self.ttl;
+            if !alive {
+                println!("registration for {} expired", address);
+            }
+            alive
+        });
+    }
+}
+
+/// Discovers backends from the workers in a [Registry].
+pub struct RegistrationDiscovery {
+    registry: Registry,
+    db: DB,
+}
+
+impl RegistrationDiscovery {
+    pub fn new(registry: Registry, db: DB) -> Self {
+        Self { registry, db }
+    }
+}
+
+#[async_trait]
+impl MembershipSource for RegistrationDiscovery {
+    async fn members(&self) -> Result<BTreeSet<String>> {
+        Ok(self
+            .registry
+            .live_workers()
+            .iter()
+            .map(|w| w.address.to_string())
+            .collect())
+    }
+}
+
+#[async_trait]
+impl ServiceDiscovery for RegistrationDiscovery {
+    async fn discover(&self) -> Result<(BTreeSet<Backend>, HashMap<u64, bool>)> {
+        assign_servers(&self.db, self.members().await?).await
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct Heartbeat {
+    address: SocketAddr,
+}
+
+/// HTTP API workers use to announce themselves:
+///
+/// - `POST /register` with a [Registration] body registers (or re-registers).
+/// - `POST /heartbeat` with `{"address": ...}` keeps the registration alive,
+///   a 404 means the worker has expired and must register again.
+/// - `POST /deregister` with `{"address": ...}` removes the worker.
+/// - `GET /workers` lists the live workers.
+///
+/// The `POST`s need the token for the address they're about in
+/// `Authorization: Bearer ...`, so a worker can only speak for itself.
+pub struct RegistrationApi {
+    registry: Registry,
+    tokens: WorkerTokens,
+}
+
+impl RegistrationApi {
+    pub fn new(registry: Registry, tokens: WorkerTokens) -> Self {
+        Self { registry, tokens }
+    }
+
+    /// Whether `authorization` holds the token for `address`.
+    fn authorized(&self, address: &SocketAddr, authorization: Option<&str>) -> bool {
+        let token = authorization.and_then(|a| a.strip_prefix("Bearer "));
+        match (token, self.tokens.get(address)) {
+            (Some(token), Some(expected)) => same_token(token, expected),
+            _ => false,
+        }
+    }
+
+    async fn handle(&self, session: &mut ServerSession) -> (StatusCode, String) {
+        let method = session.req_header().method.clone();
+        let path = session.req_header().uri.path().to_string();
+        let authorization = session
+            .req_header()
+            .headers
+            .get("Authorization")
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_string());
+        let authorization = authorization.as_deref();
+        let unauthorized = (StatusCode::UNAUTHORIZED, "unauthorized".to_string());
+        match (method, path.as_str()) {
+            (Method::GET, "/workers") => (
+                StatusCode::OK,
+                serde_json::to_string(&self.registry.live_workers()).unwrap(),
+            ),
+            (Method::POST, "/register") => match read_json::<Registration>(session).await {
+                Ok(registration) if !self.authorized(&registration.address, authorization) => {
+                    unauthorized
+                }
+                Ok(registration) => {
+                    println!("registered worker {:?}", registration);
+                    self.registry.register(registration);
+                    (StatusCode::OK, String::new())
+                }
+                Err(e) => (StatusCode::BAD_REQUEST, e),
+            },
+            (Method::POST, "/heartbeat") => match read_json::<Heartbeat>(session).await {
+                Ok(hb) if !self.authorized(&hb.address, authorization) => unauthorized,
+                Ok(hb) if self.registry.heartbeat(&hb.address) => (StatusCode::OK, String::new()),
+                Ok(_) => (StatusCode::NOT_FOUND, "not registered".to_string()),
+                Err(e) => (StatusCode::BAD_REQUEST, e),
+            },
+            (Method::POST, "/deregister") => match read_json::<Heartbeat>(session).await {
+                Ok(hb) if !self.authorized(&hb.address, authorization) => unauthorized,
+                Ok(hb) if self.registry.deregister(&hb.address) => (StatusCode::OK, String::new()),
+                Ok(_) => (StatusCode::NOT_FOUND, "not registered".to_string()),
+                Err(e) => (StatusCode::BAD_REQUEST, e),
+            },
+            _ => (StatusCode::NOT_FOUND, "not found".to_string()),
+        }
+    }
+}
+
+#[async_trait]
+impl ServeHttp for RegistrationApi {
+    async fn response(&self, session: &mut ServerSession) -> Response<Vec<u8>> {
+        let (status, body) = self.handle(session).await;
+        json_response(status, body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registration(addr: &str) -> Registration {
+        Registration {
+            address: addr.parse().unwrap(),
+            capacity: Some(10),
+            version: Some("v1".to_string()),
+        }
+    }
+
+    #[test]
+    fn test_register_and_heartbeat() {
+        let registry = Registry::new(Duration::from_secs(60));
+        registry.register(registration("127.0.0.1:8001"));
+        registry.register(registration("127.0.0.1:8002"));
+
+        assert_eq!(
+            registry.live_workers(),
+            vec![
+                registration("127.0.0.1:8001"),
+                registration("127.0.0.1:8002")
+            ]
+        );
+        assert!(registry.heartbeat(&"127.0.0.1:8001".parse().unwrap()));
+        assert!(!registry.heartbeat(&"127.0.0.1:8003".parse().unwrap()));
+
+        assert!(registry.deregister(&"127.0.0.1:8002".parse().unwrap()));
+        assert_eq!(
+            registry.live_workers(),
+            vec![registration("127.0.0.1:8001")]
+        );
+    }
+
+    #[test]
+    fn test_registration_expires() {
+        let registry = Registry::new(Duration::from_secs(5));
+        registry.register(registration("127.0.0.1:8001"));
+
+        registry.expire(Instant::now() + Duration::from_secs(4));
+        assert_eq!(registry.live_workers().len(), 1);
+
+        registry.expire(Instant::now() + Duration::from_secs(6));
+        assert!(registry.live_workers().is_empty());
+        assert!(!registry.heartbeat(&"127.0.0.1:8001".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_authorized() {
+        let tokens = WorkerTokens::from([
+            ("127.0.0.1:8001".parse().unwrap(), "secret1".to_string()),
+            ("127.0.0.1:8002".parse().unwrap(), "secret2".to_string()),
+        ]);
+        let api = RegistrationApi::new(Registry::new(Duration::from_secs(60)), tokens);
+        let worker1 = "127.0.0.1:8001".parse().unwrap();
+
+        assert!(api.authorized(&worker1, Some("Bearer secret1")));
+        assert!(!api.authorized(&worker1, None));
+        assert!(!api.authorized(&worker1, Some("secret1")));
+        // One worker's token doesn't let it register (or deregister) another.
+        assert!(!api.authorized(&worker1, Some("Bearer secret2")));
+        assert!(!api.authorized(&"127.0.0.1:8003".parse().unwrap(), Some("Bearer secret1")));
+    }
+}
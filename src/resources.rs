@@ -0,0 +1,147 @@
+use crate::health_check::Usage;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+/// Slice loads worked out from resources are in thousandths of their server's
+/// capacity.
+const SCALE: f64 = 1000.0;
+
+/// The resource the pool is closest to running out of, out of those servers
+/// report a capacity for.
+pub fn most_constrained(usage: &HashMap<SocketAddr, Usage>) -> Option<String> {
+    let mut used: BTreeMap<&str, f64> = BTreeMap::new();
+    let mut capacity: BTreeMap<&str, f64> = BTreeMap::new();
+    for usage in usage.values() {
+        for (name, amount) in &usage.capacity {
+            *capacity.entry(name).or_default() += amount;
+        }
+        for slice in usage.slices.values() {
+            for (name, amount) in &slice.resources {
+                *used.entry(name).or_default() += amount;
+            }
+        }
+    }
+    capacity
+        .into_iter()
+        .filter(|(_, capacity)| *capacity > 0.0)
+        .map(|(name, capacity)| (name, used.get(name).copied().unwrap_or(0.0) / capacity))
+        .max_by(|a, b| a.1.total_cmp(&b.1).then(b.0.cmp(a.0)))
+        .map(|(name, _)| name.to_string())
+}
+
+/// A server's capacity in the same units as [slice_loads]. Without reported
+/// capacities the best guess is the mean server load.
+pub fn server_capacity(usage: &HashMap<SocketAddr, Usage>) -> f64 {
+    if most_constrained(usage).is_some() {
+        return SCALE;
+    }
+    let loads = slice_loads(usage);
+    let total: u32 = loads.values().flat_map(|slices| slices.values()).sum();
+    total as f64 / loads.len().max(1) as f64
+}
+
+/// The load of each slice on each server to balance with. That's the slice's
+/// use of the most constrained resource relative to its server's capacity, or
+/// the plain `load` when the slice doesn't report that resource. Servers that
+/// don't report a capacity for it are taken to have an average one.
+pub fn slice_loads(usage: &HashMap<SocketAddr, Usage>) -> HashMap<SocketAddr, HashMap<u16, u32>> {
+    let dimension = most_constrained(usage);
+    let average_capacity = dimension.as_ref().map(|name| {
+        let capacities: Vec<_> = usage
+            .values()
+            .filter_map(|usage| usage.capacity.get(name))
+            .collect();
+        capacities.iter().copied().sum::<f64>() / capacities.len() as f64
+    });
+
+    usage
+        .iter()
+        .map(|(&server, usage)| {
+            let capacity = dimension.as_ref().and_then(|name| {
+                usage
+                    .capacity
+                    .get(name)
+                    .copied()
+                    .filter(|c| *c > 0.0)
+                    .or(average_capacity)
+                    .map(|capacity| (name, capacity))
+            });
+            let slices = usage
+                .slices
+                .iter()
+                .map(|(&slice, slice_usage)| {
+                    let amount = capacity.and_then(|(name, capacity)| {
+                        Some((slice_usage.resources.get(name)?, capacity))
+                    });
+                    let load = match amount {
+                        Some((amount, capacity)) => (amount / capacity * SCALE).round() as u32,
+                        None => slice_usage.load,
+                    };
+                    (slice, load)
+                })
+                .collect();
+            (server, slices)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn test_most_constrained_dimension() {
+        let usage: HashMap<SocketAddr, Usage> = HashMap::from([
+            (
+                addr(1),
+                serde_json::from_str(
+                    r#"{
+                        "slices": {
+                            "0": {"resources": {"cpu": 1.0, "memory": 100, "connections": 10}},
+                            "1": {"resources": {"cpu": 0.5, "memory": 900}}
+                        },
+                        "capacity": {"cpu": 4, "memory": 2000}
+                    }"#,
+                )
+                .unwrap(),
+            ),
+            (
+                addr(2),
+                serde_json::from_str(
+                    r#"{
+                        "slices": {
+                            "2": {"resources": {"cpu": 2.5, "memory": 100}},
+                            "3": {"load": 40, "resources": {"memory": 10}}
+                        },
+                        "capacity": {"cpu": 10, "memory": 2000}
+                    }"#,
+                )
+                .unwrap(),
+            ),
+        ]);
+
+        // cpu is at 4/14 of capacity, memory 1110/4000, and connections have
+        // no capacity so don't count.
+        assert_eq!(most_constrained(&usage), Some("cpu".to_string()));
+        // Each relative to its own server's cpu, or the plain load without
+        // any cpu.
+        let loads = slice_loads(&usage);
+        assert_eq!(loads[&addr(1)], HashMap::from([(0, 250), (1, 125)]));
+        assert_eq!(loads[&addr(2)], HashMap::from([(2, 250), (3, 40)]));
+    }
+
+    #[test]
+    fn test_plain_load() {
+        let usage: HashMap<SocketAddr, Usage> = HashMap::from([(
+            addr(1),
+            serde_json::from_str(r#"{"slices":{"0":{"load":7}}}"#).unwrap(),
+        )]);
+        assert_eq!(most_constrained(&usage), None);
+        assert_eq!(slice_loads(&usage)[&addr(1)], HashMap::from([(0, 7)]));
+    }
+}
@@ -2,44 +2,340 @@ use pingora_load_balancing::selection::BackendIter;
 use pingora_load_balancing::selection::BackendSelection;
 use pingora_load_balancing::Backend;
 use std::collections::BTreeSet;
+use std::collections::HashMap;
 use std::hash::{DefaultHasher, Hash, Hasher};
-use std::sync::Arc;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, RwLock};
 
-use crate::slice_assignments::NUM_SLICES;
+use crate::health_check::HealthStatus;
+use crate::slice_assignments::SliceAssignments;
+
+/// What to do with requests for a slice whose owner is unhealthy or missing.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum UnhealthyOwner {
+    /// Don't send them anywhere, the proxy responds with a 503.
+    #[default]
+    FailFast,
+    /// Send each request to the first healthy server in a fixed order for the
+    /// slice, going back to the owner as soon as it's healthy.
+    Fallback,
+    /// Send them to the healthy server with the fewest slices, and keep
+    /// sending them there until the owner is healthy again. This is local to
+    /// each load balancer, the stored assignments don't change.
+    Reassign,
+}
+
+impl FromStr for UnhealthyOwner {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fail-fast" => Ok(UnhealthyOwner::FailFast),
+            "fallback" => Ok(UnhealthyOwner::Fallback),
+            "reassign" => Ok(UnhealthyOwner::Reassign),
+            _ => Err(format!(
+                "unknown unhealthy owner policy {}, expected fail-fast, fallback or reassign",
+                s
+            )),
+        }
+    }
+}
+
+/// The latest slice assignments, attached to every backend. The set of
+/// backends (and so the selection) is only rebuilt when servers come or go, so
+/// slices that move between existing servers are looked up here when routing.
+#[derive(Clone, Default)]
+pub struct SharedAssignments {
+    assignments: Arc<RwLock<Option<SliceAssignments>>>,
+    pub policy: UnhealthyOwner,
+    /// Slices temporarily routed away from their owner, see
+    /// [UnhealthyOwner::Reassign].
+    reassigned: Arc<Mutex<HashMap<u16, SocketAddr>>>,
+}
+
+impl SharedAssignments {
+    pub fn store(&self, assignments: &SliceAssignments) {
+        *self.assignments.write().unwrap() = Some(assignments.clone());
+    }
+
+    /// The slice a routing key belongs to, see
+    /// [SliceAssignments::slice_for_key].
+    pub fn slice_for_key(&self, key: &[u8]) -> Option<u16> {
+        Some(
+            self.assignments
+                .read()
+                .unwrap()
+                .as_ref()?
+                .slice_for_key(key),
+        )
+    }
+
+    /// The slice a routing key belongs to and the server that owns it.
+    pub fn route(&self, key: &[u8]) -> Option<(u16, SocketAddr)> {
+        let guard = self.assignments.read().unwrap();
+        let assignments = guard.as_ref()?;
+        let slice = assignments.slice_for_key(key);
+        Some((slice, assignments.owner(slice)?))
+    }
+
+    /// Whether `server` owns `slice`, see [SliceAssignments::owns]. `None`
+    /// until assignments have been stored.
+    pub fn owns(&self, server: &SocketAddr, slice: u16) -> Option<bool> {
+        Some(
+            self.assignments
+                .read()
+                .unwrap()
+                .as_ref()?
+                .owns(server, slice),
+        )
+    }
+
+    /// Where to send `slice` while its owner is unhealthy, out of the healthy
+    /// `servers`. Sticks with an earlier choice while it's still healthy.
+    fn reassign(&self, slice: u16, servers: &[SocketAddr]) -> Option<SocketAddr> {
+        let mut reassigned = self.reassigned.lock().unwrap();
+        if let Some(server) = reassigned.get(&slice).filter(|s| servers.contains(s)) {
+            return Some(*server);
+        }
+        let guard = self.assignments.read().unwrap();
+        let slices = |server: &SocketAddr| {
+            let owned = guard.as_ref().map_or(0, |a| a.slice_count(server));
+            owned + reassigned.values().filter(|s| *s == server).count()
+        };
+        let server = *servers
+            .iter()
+            .min_by_key(|s| (slices(s), std::cmp::Reverse(rank(slice, s))))?;
+        println!("slice {} reassigned to {} while unhealthy", slice, server);
+        reassigned.insert(slice, server);
+        Some(server)
+    }
+
+    /// The owner of `slice` is healthy again.
+    fn restore(&self, slice: u16) {
+        if self.reassigned.lock().unwrap().remove(&slice).is_some() {
+            println!("slice {} back on its owner", slice);
+        }
+    }
+}
+
+/// Where `server` comes in the fallback order for `slice`, highest first.
+fn rank(slice: u16, server: &SocketAddr) -> u64 {
+    let mut state = DefaultHasher::new();
+    (slice, server).hash(&mut state);
+    state.finish()
+}
+
+/// Whether the health check last found `backend` healthy.
+pub fn is_healthy(backend: &Backend) -> bool {
+    backend
+        .ext
+        .get::<HealthStatus>()
+        .is_none_or(|status| status.inner.read().unwrap().is_healthy)
+}
 
 pub struct SliceSelection {
     backends: Box<[Backend]>,
+    assignments: Option<SharedAssignments>,
 }
 impl BackendSelection for SliceSelection {
     type Iter = SliceBackendIterator;
     fn build(backends: &BTreeSet<Backend>) -> Self {
         SliceSelection {
             backends: Vec::from_iter(backends.iter().cloned()).into_boxed_slice(),
+            assignments: backends
+                .first()
+                .and_then(|b| b.ext.get::<SharedAssignments>().cloned()),
         }
     }
+    /// The slice's owner, followed by where else it can go according to the
+    /// [UnhealthyOwner] policy. Owners that aren't in the current set of
+    /// backends are treated as unhealthy.
     fn iter(self: &Arc<Self>, key: &[u8]) -> Self::Iter {
-        let mut state = DefaultHasher::new();
-        key.hash(&mut state);
-        let slice: u16 = (state.finish() % NUM_SLICES as u64) as u16;
-        if self.backends.is_empty() {
-            return SliceBackendIterator { backend: None };
-        }
-        for backend in self.backends.iter() {
-            let slices = backend.ext.get::<BTreeSet<u16>>().unwrap();
-            if slices.contains(&slice) {
-                return SliceBackendIterator {
-                    backend: Some(backend.clone()),
-                };
+        let mut iter = SliceBackendIterator {
+            backends: Vec::new(),
+            next: 0,
+        };
+        let Some(shared) = self.assignments.as_ref() else {
+            return iter;
+        };
+        let Some((slice, owner)) = shared.route(key) else {
+            return iter;
+        };
+        let owner = self
+            .backends
+            .iter()
+            .find(|b| b.addr.as_inet() == Some(&owner));
+        let others = self
+            .backends
+            .iter()
+            .filter(|b| owner.is_none_or(|o| o.addr != b.addr));
+        match shared.policy {
+            UnhealthyOwner::FailFast => iter.backends.extend(owner.cloned()),
+            UnhealthyOwner::Fallback => {
+                let mut others: Vec<_> = others.collect();
+                others.sort_by_key(|b| {
+                    std::cmp::Reverse(b.addr.as_inet().map_or(0, |a| rank(slice, a)))
+                });
+                iter.backends
+                    .extend(owner.into_iter().chain(others).cloned());
             }
+            UnhealthyOwner::Reassign => match owner.filter(|o| is_healthy(o)) {
+                Some(owner) => {
+                    shared.restore(slice);
+                    iter.backends.push(owner.clone());
+                }
+                None => {
+                    let healthy: Vec<_> = others
+                        .filter(|b| is_healthy(b))
+                        .filter_map(|b| b.addr.as_inet().copied())
+                        .collect();
+                    if let Some(server) = shared.reassign(slice, &healthy) {
+                        iter.backends.extend(
+                            self.backends
+                                .iter()
+                                .find(|b| b.addr.as_inet() == Some(&server))
+                                .cloned(),
+                        );
+                    }
+                }
+            },
         }
-        panic!("No backend found for slice: {}", slice);
+        iter
     }
 }
 pub struct SliceBackendIterator {
-    backend: Option<Backend>,
+    backends: Vec<Backend>,
+    next: usize,
 }
 impl BackendIter for SliceBackendIterator {
     fn next(&mut self) -> Option<&Backend> {
-        self.backend.as_ref()
+        let backend = self.backends.get(self.next);
+        self.next += 1;
+        backend
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::health_check::WorkerHealthCheck;
+    use pingora_load_balancing::health_check::HealthCheck;
+    use tokio::io::AsyncReadExt;
+    use tokio::io::AsyncWriteExt;
+
+    fn build_selection(policy: UnhealthyOwner) -> (Arc<SliceSelection>, SliceAssignments) {
+        let servers: Vec<SocketAddr> = (1..=3)
+            .map(|i| format!("127.0.0.1:800{}", i).parse().unwrap())
+            .collect();
+        build_selection_with(policy, servers)
+    }
+
+    fn build_selection_with(
+        policy: UnhealthyOwner,
+        servers: Vec<SocketAddr>,
+    ) -> (Arc<SliceSelection>, SliceAssignments) {
+        let assignments = SliceAssignments::new(servers);
+        let shared = SharedAssignments {
+            policy,
+            ..Default::default()
+        };
+        shared.store(&assignments);
+        let backends = assignments
+            .to_backends(&Default::default())
+            .into_iter()
+            .map(|mut backend| {
+                backend.ext.insert(shared.clone());
+                backend
+            })
+            .collect();
+        (Arc::new(SliceSelection::build(&backends)), assignments)
+    }
+
+    /// Health check `server` the way the load balancer does.
+    async fn check(selection: &SliceSelection, server: SocketAddr) {
+        let backend = selection
+            .backends
+            .iter()
+            .find(|b| b.addr.as_inet() == Some(&server))
+            .unwrap();
+        let _ = WorkerHealthCheck::default().check(backend).await;
+    }
+
+    /// A server that answers one health check.
+    async fn answer_once(server: SocketAddr) -> tokio::task::JoinHandle<()> {
+        let listener = tokio::net::TcpListener::bind(server).await.unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = [0; 1024];
+            let _ = stream.read(&mut request).await;
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+                .await
+                .unwrap();
+        })
+    }
+
+    fn selected(selection: &Arc<SliceSelection>, key: &[u8]) -> Vec<SocketAddr> {
+        let mut iter = selection.iter(key);
+        let mut backends = Vec::new();
+        while let Some(backend) = iter.next() {
+            backends.push(*backend.addr.as_inet().unwrap());
+        }
+        backends
+    }
+
+    #[tokio::test]
+    async fn test_unhealthy_owner_policies() {
+        let key = b"user-1";
+        let (selection, assignments) = build_selection(UnhealthyOwner::FailFast);
+        let owner = assignments.owner(assignments.slice_for_key(key)).unwrap();
+        assert_eq!(selected(&selection, key), vec![owner]);
+
+        let (selection, _) = build_selection(UnhealthyOwner::Fallback);
+        let order = selected(&selection, key);
+        assert_eq!(order.len(), 3);
+        assert_eq!(order[0], owner);
+        assert_eq!(selected(&selection, key), order);
+
+        // Nothing listens on these, so checks fail until a server answers.
+        let servers: Vec<SocketAddr> = (0..3)
+            .map(|_| {
+                std::net::TcpListener::bind("127.0.0.1:0")
+                    .unwrap()
+                    .local_addr()
+                    .unwrap()
+            })
+            .collect();
+        let (selection, assignments) = build_selection_with(UnhealthyOwner::Reassign, servers);
+        let owner = assignments.owner(assignments.slice_for_key(key)).unwrap();
+        assert_eq!(selected(&selection, key), vec![owner]);
+        check(&selection, owner).await;
+        let reassigned = selected(&selection, key);
+        assert_eq!(reassigned.len(), 1);
+        assert_ne!(reassigned[0], owner);
+        assert_eq!(selected(&selection, key), reassigned);
+        let answered = answer_once(owner).await;
+        check(&selection, owner).await;
+        answered.await.unwrap();
+        assert_eq!(selected(&selection, key), vec![owner]);
+    }
+
+    #[test]
+    fn test_missing_owner() {
+        let (selection, assignments) = build_selection(UnhealthyOwner::Fallback);
+        let mut moved = assignments.clone();
+        moved.servers[0] = "127.0.0.1:9000".parse().unwrap();
+        selection.assignments.as_ref().unwrap().store(&moved);
+        let key = (0..)
+            .map(|i| format!("user-{}", i))
+            .find(|k| moved.owner(moved.slice_for_key(k.as_bytes())) == Some(moved.servers[0]))
+            .unwrap();
+        // No panic, every server that is there gets tried.
+        assert_eq!(selected(&selection, key.as_bytes()).len(), 3);
+
+        let (selection, _) = build_selection(UnhealthyOwner::FailFast);
+        selection.assignments.as_ref().unwrap().store(&moved);
+        assert!(selected(&selection, key.as_bytes()).is_empty());
     }
 }
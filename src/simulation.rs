@@ -0,0 +1,295 @@
+use crate::health_check::Usage;
+use crate::rebalance::RebalanceStrategy;
+use crate::slice_assignments::Balance;
+use crate::slice_assignments::SliceAssignments;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+/// A request in a trace, timestamps are milliseconds.
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct TraceEntry {
+    pub key: String,
+    pub timestamp: i64,
+    #[serde(default = "default_cost")]
+    pub cost: u32,
+}
+
+fn default_cost() -> u32 {
+    1
+}
+
+/// The servers at the start of a simulation and how they change, eg:
+///
+/// ```json
+/// {
+///     "servers": ["10.0.0.1:8000", "10.0.0.2:8000"],
+///     "events": [{"at": 60000, "action": "add", "server": "10.0.0.3:8000"}]
+/// }
+/// ```
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct Scenario {
+    pub servers: Vec<SocketAddr>,
+    #[serde(default)]
+    pub events: Vec<Event>,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct Event {
+    pub at: i64,
+    #[serde(flatten)]
+    pub change: Change,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(tag = "action", rename_all = "lowercase")]
+pub enum Change {
+    Add { server: SocketAddr },
+    Remove { server: SocketAddr },
+}
+
+/// What happened in one tick of a simulation.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct TickReport {
+    pub tick: usize,
+    pub start: i64,
+    /// Total cost of the requests each server got.
+    pub loads: BTreeMap<SocketAddr, u32>,
+    pub imbalance: f32,
+    /// Slices that changed server at the end of the tick, from membership
+    /// changes and rebalancing.
+    pub slices_moved: usize,
+    /// Keys seen so far that now go to a different server.
+    pub keys_remapped: usize,
+}
+
+/// Replays a trace against the real assignment code, tick by tick. Each tick
+/// the membership events due are applied, the tick's requests are routed and
+/// then, given a strategy, the load they put on each slice is rebalanced as
+/// if workers had reported it.
+pub struct Simulation<'a> {
+    pub assignments: SliceAssignments,
+    servers: Vec<SocketAddr>,
+    events: Vec<Event>,
+    strategy: Option<&'a dyn RebalanceStrategy>,
+    tick_length: i64,
+    keys: BTreeSet<String>,
+}
+
+impl<'a> Simulation<'a> {
+    pub fn new(
+        scenario: Scenario,
+        tick_length: i64,
+        strategy: Option<&'a dyn RebalanceStrategy>,
+    ) -> Self {
+        let mut events = scenario.events;
+        events.sort_by_key(|e| e.at);
+        Self {
+            assignments: SliceAssignments::new(scenario.servers.clone()),
+            servers: scenario.servers,
+            events,
+            strategy,
+            tick_length: tick_length.max(1),
+            keys: BTreeSet::new(),
+        }
+    }
+
+    // How many ticks past the end of the trace events are still applied, so a
+    // typo'd timestamp doesn't run the simulation for ever.
+    const MAX_IDLE_TICKS: i64 = 10_000;
+
+    /// Run the whole trace, which must be in timestamp order, and any events
+    /// after it.
+    pub fn run(&mut self, trace: impl IntoIterator<Item = TraceEntry>) -> Vec<TickReport> {
+        let mut trace = trace.into_iter().peekable();
+        let start = match (trace.peek(), self.events.first()) {
+            (Some(entry), _) => entry.timestamp,
+            (None, Some(event)) => event.at,
+            (None, None) => return Vec::new(),
+        };
+        let mut reports = Vec::new();
+        let mut tick_start = start;
+        let mut horizon = None;
+        while trace.peek().is_some() || !self.events.is_empty() {
+            if trace.peek().is_none() {
+                let horizon = *horizon.get_or_insert(
+                    tick_start
+                        .saturating_add(Self::MAX_IDLE_TICKS.saturating_mul(self.tick_length)),
+                );
+                let late = self.events.partition_point(|e| e.at < horizon);
+                for event in self.events.drain(late..) {
+                    println!("ignoring event at {}, too long after the trace", event.at);
+                }
+                if self.events.is_empty() {
+                    break;
+                }
+            }
+            let end = tick_start + self.tick_length;
+            let mut requests = Vec::new();
+            while let Some(entry) = trace.next_if(|e| e.timestamp < end) {
+                requests.push(entry);
+            }
+            reports.push(self.tick(reports.len(), tick_start, requests));
+            tick_start = end;
+        }
+        reports
+    }
+
+    fn tick(&mut self, tick: usize, start: i64, requests: Vec<TraceEntry>) -> TickReport {
+        let before = self.assignments.clone();
+        let end = start + self.tick_length;
+        let due = self.events.iter().take_while(|e| e.at < end).count();
+        for event in self.events.drain(..due) {
+            match event.change {
+                Change::Add { server } if !self.servers.contains(&server) => {
+                    self.servers.push(server)
+                }
+                Change::Remove { server } => self.servers.retain(|s| *s != server),
+                _ => {}
+            }
+        }
+        self.assignments.update(self.servers.clone());
+
+        let mut usage: HashMap<SocketAddr, Usage> = self
+            .servers
+            .iter()
+            .map(|&server| (server, Usage::default()))
+            .collect();
+        for request in requests {
+            let slice = self.assignments.slice_for_key(request.key.as_bytes());
+            if let Some(owner) = self.assignments.owner(slice) {
+                let slice_usage = usage
+                    .entry(owner)
+                    .or_default()
+                    .slices
+                    .entry(slice)
+                    .or_default();
+                slice_usage.load += request.cost;
+            }
+            self.keys.insert(request.key);
+        }
+        let (loads, _) = Balance::usage_stats(&usage);
+        let imbalance = Balance::calculate_imbalance(&loads);
+
+        if let Some(strategy) = self.strategy {
+            for m in strategy.find_moves(&self.assignments, &usage) {
+                self.assignments
+                    .move_slice(m.slice_id, m.to_server)
+                    .unwrap();
+            }
+        }
+
+        let slices_moved = self
+            .assignments
+            .slices()
+            .filter(|&s| before.owner(s) != self.assignments.owner(s))
+            .count();
+        let server = |assignments: &SliceAssignments, key: &String| {
+            assignments.owner(assignments.slice_for_key(key.as_bytes()))
+        };
+        let keys_remapped = self
+            .keys
+            .iter()
+            .filter(|key| server(&before, key) != server(&self.assignments, key))
+            .count();
+        TickReport {
+            tick,
+            start,
+            loads: loads.into_iter().collect(),
+            imbalance,
+            slices_moved,
+            keys_remapped,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rebalance::Greedy;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    fn trace(ticks: i64) -> Vec<TraceEntry> {
+        (0..ticks * 100)
+            .map(|i| TraceEntry {
+                key: format!("user-{}", i % 50),
+                timestamp: i * 10,
+                cost: if i % 50 == 0 { 100 } else { 1 },
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_membership_events() {
+        let scenario = Scenario {
+            servers: vec![addr(1), addr(2), addr(3)],
+            events: vec![Event {
+                at: 1500,
+                change: Change::Remove { server: addr(3) },
+            }],
+        };
+        let mut simulation = Simulation::new(scenario, 1000, None);
+        let reports = simulation.run(trace(3));
+        assert_eq!(reports.len(), 3);
+        assert_eq!(reports[0].slices_moved, 0);
+        assert_eq!(reports[0].loads.len(), 3);
+
+        // Only slices and keys of the removed server move.
+        let on_removed =
+            SliceAssignments::new(vec![addr(1), addr(2), addr(3)]).slice_count(&addr(3));
+        assert_eq!(reports[1].slices_moved, on_removed);
+        assert!(reports[1].keys_remapped > 0);
+        assert_eq!(reports[1].loads.len(), 2);
+        assert_eq!(reports[2].slices_moved, 0);
+        assert_eq!(reports[2].keys_remapped, 0);
+        assert_eq!(reports[2].loads.values().sum::<u32>(), 100 * 2 + 98);
+    }
+
+    #[test]
+    fn test_rebalancing() {
+        let scenario = Scenario {
+            servers: vec![addr(1), addr(2)],
+            events: Vec::new(),
+        };
+        let mut simulation = Simulation::new(scenario, 1000, Some(&Greedy));
+        let reports = simulation.run(trace(4));
+        assert!(reports.iter().any(|r| r.slices_moved > 0));
+        assert!(reports.last().unwrap().imbalance <= reports[0].imbalance);
+    }
+
+    #[test]
+    fn test_events_without_trace() {
+        let scenario = Scenario {
+            servers: vec![addr(1), addr(2), addr(3)],
+            events: vec![
+                Event {
+                    at: 1500,
+                    change: Change::Remove { server: addr(2) },
+                },
+                Event {
+                    at: 2500,
+                    change: Change::Remove { server: addr(3) },
+                },
+                Event {
+                    at: i64::MAX,
+                    change: Change::Remove { server: addr(1) },
+                },
+            ],
+        };
+        let mut simulation = Simulation::new(scenario, 1000, None);
+        let reports = simulation.run(Vec::new());
+        // The event far past the end is dropped rather than ticked towards.
+        assert_eq!(reports.len(), 2);
+        assert_eq!(reports[0].start, 1500);
+        assert!(reports[0].slices_moved > 0);
+        assert!(reports[1].slices_moved > 0);
+        assert_eq!(
+            simulation.assignments.slice_count(&addr(1)),
+            simulation.assignments.slices().count()
+        );
+    }
+}
@@ -1,20 +1,83 @@
+use crate::churn::Churn;
+use crate::constraints::Constraints;
+use crate::constraints::Violation;
 use crate::health_check::HealthStatus;
+use crate::health_check::ServerStates;
+use crate::health_check::Usage;
+use crate::membership::Membership;
+use crate::resources;
 use log::info;
 use pingora_ketama::Bucket;
 use pingora_ketama::Continuum;
 use pingora_load_balancing::Backend;
 use std::cmp::Ordering;
+use std::collections::BTreeMap;
 use std::collections::BTreeSet;
 use std::collections::HashMap;
+use std::hash::DefaultHasher;
+use std::hash::Hash;
+use std::hash::Hasher;
 use std::net::SocketAddr;
 use std::net::ToSocketAddrs;
+use std::time::Duration;
 
 pub const NUM_SLICES: u16 = 100;
 
+/// The original slice a routing key hashes to, before any splits.
+fn base_slice(key: &[u8]) -> u16 {
+    let mut state = DefaultHasher::new();
+    key.hash(&mut state);
+    (state.finish() % NUM_SLICES as u64) as u16
+}
+
+/// Servers can be taken out of rotation ahead of maintenance.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Cordon {
+    /// The server keeps the slices it has but isn't given new ones.
+    Cordoned,
+    /// Like cordoned, and its slices are gradually moved to other servers.
+    Draining,
+}
+
+/// The slices split off from a slice, see [SliceAssignments::split].
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Split {
+    /// Oldest first.
+    pub children: Vec<u16>,
+    /// When the slice was last split, milliseconds since the epoch.
+    pub at: i64,
+}
+
+/// A slice that has been merged into another, see [SliceAssignments::merge].
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Merge {
+    pub into: u16,
+    /// When the slices were merged, milliseconds since the epoch.
+    pub at: i64,
+}
+
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct SliceAssignments {
     pub servers: Vec<SocketAddr>,
     pub assignments: Vec<usize>,
+    #[serde(default)]
+    pub membership: Membership,
+    #[serde(default)]
+    pub cordons: BTreeMap<SocketAddr, Cordon>,
+    #[serde(default)]
+    pub constraints: Constraints,
+    #[serde(default)]
+    pub churn: Churn,
+    /// Slices that have been split. Split off slices are numbered from
+    /// [NUM_SLICES] up.
+    #[serde(default)]
+    pub splits: BTreeMap<u16, Split>,
+    /// Slices merged into another slice, their keys are routed as if they
+    /// were on that slice. A merged slice always has the same owner as the
+    /// slice it's merged into.
+    #[serde(default)]
+    pub merges: BTreeMap<u16, Merge>,
 }
 
 impl SliceAssignments {
@@ -36,75 +99,402 @@ impl SliceAssignments {
         Self {
             servers,
             assignments,
+            membership: Membership::default(),
+            cordons: BTreeMap::new(),
+            constraints: Constraints::default(),
+            churn: Churn::default(),
+            splits: BTreeMap::new(),
+            merges: BTreeMap::new(),
         }
     }
+
+    /// Like [SliceAssignments::new], with slices moved to meet `constraints`
+    /// where possible.
+    pub fn with_constraints(servers: Vec<SocketAddr>, constraints: Constraints) -> Self {
+        let mut assignments = Self::new(servers);
+        assignments.constraints = constraints;
+        assignments.enforce_constraints();
+        assignments
+    }
+
     pub fn update(&mut self, servers: Vec<SocketAddr>) {
-        // If servers list is identical, no changes needed
-        if servers == self.servers {
+        // If servers list is identical, no changes needed. With no servers
+        // there's nowhere to move slices to, so leave them where they are.
+        if servers == self.servers || servers.is_empty() {
             return;
         }
 
-        // Create buckets for consistent hashing
-        let buckets: Vec<_> = servers
-            .iter()
-            .filter_map(|s| s.ip().is_ipv4().then_some(Bucket::new(*s, 1)))
-            .collect();
-        let ring = Continuum::new(&buckets);
+        // Cordons only apply to servers that are still around
+        self.cordons.retain(|s, _| servers.contains(s));
 
-        // Find servers that were removed
-        let removed_servers: Vec<_> = self
-            .servers
+        // Create buckets for consistent hashing, cordoned servers don't take
+        // new slices unless there's nowhere else for them to go
+        let open: Vec<_> = servers
             .iter()
-            .enumerate()
-            .filter(|(_, s)| !servers.contains(s))
-            .map(|(i, _)| i)
+            .filter(|s| !self.cordons.contains_key(s))
             .collect();
+        let buckets: Vec<_> = if open.is_empty() {
+            servers.iter().collect()
+        } else {
+            open
+        }
+        .into_iter()
+        .filter_map(|s| s.ip().is_ipv4().then_some(Bucket::new(*s, 1)))
+        .collect();
+        let ring = Continuum::new(&buckets);
 
-        // Re-assign only slices that were assigned to removed servers
+        // Re-assign only slices that were assigned to removed servers, the
+        // rest stay put but their server may be at a new position in the list
         let mut assignments = self.assignments.clone();
         for (i, assignment) in assignments.iter_mut().enumerate() {
-            if removed_servers.contains(assignment) {
-                let addr = ring.get_addr(&mut ring.node_idx(&[i as u8])).unwrap();
-                *assignment = servers.iter().position(|s| s == addr).unwrap();
+            match servers.iter().position(|s| *s == self.servers[*assignment]) {
+                Some(position) => *assignment = position,
+                None => {
+                    let addr = ring.get_addr(&mut ring.node_idx(&[i as u8])).unwrap();
+                    *assignment = servers.iter().position(|s| s == addr).unwrap();
+                }
             }
         }
 
         self.servers = servers;
         self.assignments = assignments;
+        self.follow_merges();
+        self.enforce_constraints();
     }
 
-    pub fn move_load(&mut self) {
-        let moves = Balance::find_best_moves(&self.to_backends());
-        if !moves.is_empty() {
-            info!("Top most beneficial moves:");
-            for mov in moves {
-                info!(
-                    "Move slice {} from {} to {} (benefit: {:.3})",
-                    mov.slice_id, mov.from_server, mov.to_server, mov.benefit
-                );
-                self.assignments[mov.slice_id as usize] = self
+    /// Whether `slice` is allowed on `server`. Pins to servers that aren't
+    /// members are ignored so the slice can still be placed somewhere.
+    pub fn allows(&self, slice: u16, server: &SocketAddr) -> bool {
+        if let Some(pin) = self.constraints.pins.get(&slice) {
+            if self.servers.contains(pin) {
+                return pin == server;
+            }
+        }
+        !self.constraints.is_forbidden(slice, server)
+    }
+
+    /// Whether `slice` can be moved to `server`: it's allowed there, the
+    /// server isn't cordoned and is below its slice limit.
+    pub fn can_take(&self, slice: u16, server: &SocketAddr) -> bool {
+        self.allows(slice, server)
+            && !self.cordons.contains_key(server)
+            && self.slice_count(server) < self.constraints.limit(server)
+    }
+
+    /// Move slices so they meet the constraints where possible, each to the
+    /// allowed server with the fewest slices. Returns the constraints that
+    /// still can't be met.
+    pub fn enforce_constraints(&mut self) -> Vec<Violation> {
+        for slice in self.slices().collect::<Vec<_>>() {
+            let owner = self.servers[self.assignments[slice as usize]];
+            let target = match self.constraints.pins.get(&slice) {
+                Some(pin) if self.servers.contains(pin) => Some(*pin),
+                _ if self.allows(slice, &owner)
+                    && self.slice_count(&owner) <= self.constraints.limit(&owner) =>
+                {
+                    None
+                }
+                _ => self
                     .servers
                     .iter()
-                    .position(|s| s == &mov.to_server)
-                    .unwrap();
+                    .filter(|&s| *s != owner && self.can_take(slice, s))
+                    .min_by_key(|s| self.slice_count(s))
+                    .copied(),
+            };
+            if let Some(target) = target.filter(|t| *t != owner) {
+                info!(
+                    "Moving slice {} from {} to {} to meet constraints",
+                    slice, owner, target
+                );
+                self.move_slice(slice, target).unwrap();
+            }
+        }
+        let violations = self.violations();
+        for violation in &violations {
+            println!("constraint violated: {}", violation);
+        }
+        violations
+    }
+
+    /// The constraints the assignments don't currently meet.
+    pub fn violations(&self) -> Vec<Violation> {
+        let mut violations = Vec::new();
+        let mut pinned: BTreeMap<SocketAddr, usize> = BTreeMap::new();
+        for (&slice, &server) in &self.constraints.pins {
+            if !self.servers.contains(&server) {
+                violations.push(Violation::PinnedServerMissing { slice, server });
+                continue;
+            }
+            // Pins win over cordons and limits, but say so.
+            if self.cordons.contains_key(&server) {
+                violations.push(Violation::PinnedServerCordoned { slice, server });
+            }
+            *pinned.entry(server).or_default() += 1;
+        }
+        for (server, pinned) in pinned {
+            let max = self.constraints.limit(&server);
+            if pinned > max {
+                violations.push(Violation::PinnedOverLimit {
+                    server,
+                    pinned,
+                    max,
+                });
+            }
+        }
+        for slice in self.slices() {
+            let server = self.servers[self.assignments[slice as usize]];
+            if !self.allows(slice, &server) {
+                violations.push(Violation::Misplaced { slice, server });
+            }
+        }
+        for server in &self.servers {
+            let (slices, max) = (self.slice_count(server), self.constraints.limit(server));
+            if slices > max {
+                violations.push(Violation::OverLimit {
+                    server: *server,
+                    slices,
+                    max,
+                });
+            }
+        }
+        violations
+    }
+
+    /// Move up to `max_moves` slices off draining servers, each to whichever
+    /// server that can take it has the fewest slices. Returns the number of
+    /// slices moved.
+    pub fn drain(&mut self, max_moves: usize) -> usize {
+        let mut moved = 0;
+        for slice in self.slices().collect::<Vec<_>>() {
+            if moved >= max_moves {
+                break;
+            }
+            let owner = self.servers[self.assignments[slice as usize]];
+            if self.cordons.get(&owner) != Some(&Cordon::Draining) {
+                continue;
+            }
+            let Some(target) = self
+                .servers
+                .iter()
+                .filter(|s| self.can_take(slice, s))
+                .min_by_key(|s| self.slice_count(s))
+                .copied()
+            else {
+                continue;
+            };
+            info!("Draining slice {} from {} to {}", slice, owner, target);
+            self.move_slice(slice, target).unwrap();
+            moved += 1;
+        }
+        moved
+    }
+
+    /// The number of slices assigned to `server`.
+    pub fn slice_count(&self, server: &SocketAddr) -> usize {
+        match self.servers.iter().position(|s| s == server) {
+            Some(i) => self
+                .slices()
+                .filter(|&slice| self.assignments[slice as usize] == i)
+                .count(),
+            None => 0,
+        }
+    }
+
+    /// The server that owns `slice`.
+    pub fn owner(&self, slice: u16) -> Option<SocketAddr> {
+        self.assignments
+            .get(self.root(slice) as usize)
+            .map(|&i| self.servers[i])
+    }
+
+    /// Whether `slice` exists, hasn't been merged away and is owned by
+    /// `server`.
+    pub fn owns(&self, server: &SocketAddr, slice: u16) -> bool {
+        (slice as usize) < self.assignments.len()
+            && !self.merges.contains_key(&slice)
+            && self.owner(slice).as_ref() == Some(server)
+    }
+
+    /// Move `slice` to the server `to`, along with any slices merged with it.
+    pub fn move_slice(&mut self, slice: u16, to: SocketAddr) -> Result<(), String> {
+        let Some(position) = self.servers.iter().position(|s| *s == to) else {
+            return Err(format!("{} is not a known server", to));
+        };
+        let root = self.root(slice);
+        let Some(assignment) = self.assignments.get_mut(root as usize) else {
+            return Err(format!("slice {} does not exist", slice));
+        };
+        *assignment = position;
+        self.follow_merges();
+        Ok(())
+    }
+
+    /// Every slice, including those split off from others, but not those
+    /// merged into another slice.
+    pub fn slices(&self) -> impl Iterator<Item = u16> + '_ {
+        (0..self.assignments.len() as u16).filter(|slice| !self.merges.contains_key(slice))
+    }
+
+    /// The slice `slice` is merged into, or itself.
+    pub fn root(&self, slice: u16) -> u16 {
+        self.merges.get(&slice).map_or(slice, |m| m.into)
+    }
+
+    /// Keep merged slices on the same server as the slice they're merged
+    /// into.
+    fn follow_merges(&mut self) {
+        for (&slice, merge) in &self.merges {
+            self.assignments[slice as usize] = self.assignments[merge.into as usize];
+        }
+    }
+
+    /// The slice a routing key belongs to. Keys hash to one of the
+    /// [NUM_SLICES] original slices, then each split of that slice sends half
+    /// of the keys still on it to the split off slice, which may itself have
+    /// been split. Keys of a merged slice go to the slice it's merged into.
+    pub fn slice_for_key(&self, key: &[u8]) -> u16 {
+        let mut slice = base_slice(key);
+        let mut split = 0;
+        while let Some(&child) = self.splits.get(&slice).and_then(|s| s.children.get(split)) {
+            let mut state = DefaultHasher::new();
+            (key, slice, split).hash(&mut state);
+            if state.finish() % 2 == 1 {
+                slice = child;
+                split = 0;
+            } else {
+                split += 1;
             }
+        }
+        self.root(slice)
+    }
+
+    /// Split `slice` in two so the halves can be moved independently. If
+    /// other slices have been merged into it the latest of those is split
+    /// back out, otherwise half of its keys go to a new slice with the same
+    /// placement constraints. Either way both halves start on the same server
+    /// so no key changes server. Returns the other half.
+    pub fn split(&mut self, slice: u16, now: i64) -> Result<u16, String> {
+        let Some(&owner) = self.assignments.get(slice as usize) else {
+            return Err(format!("slice {} does not exist", slice));
+        };
+        if let Some(merge) = self.merges.get(&slice) {
+            return Err(format!("slice {} is merged into {}", slice, merge.into));
+        }
+        let merged = self
+            .merges
+            .iter()
+            .filter(|(_, m)| m.into == slice)
+            .max_by_key(|(&merged, m)| (m.at, merged))
+            .map(|(&merged, _)| merged);
+        let other = match merged {
+            Some(merged) => {
+                self.merges.remove(&merged);
+                merged
+            }
+            None => {
+                let child = u16::try_from(self.assignments.len())
+                    .map_err(|_| "too many slices to split".to_string())?;
+                self.assignments.push(owner);
+                if let Some(&pin) = self.constraints.pins.get(&slice) {
+                    self.constraints.pins.insert(child, pin);
+                }
+                if let Some(forbidden) = self.constraints.forbidden.get(&slice).cloned() {
+                    self.constraints.forbidden.insert(child, forbidden);
+                }
+                self.splits.entry(slice).or_default().children.push(child);
+                child
+            }
+        };
+        self.splits.entry(slice).or_default().at = now;
+        self.splits.entry(other).or_default().at = now;
+        Ok(other)
+    }
+
+    /// Whether `slice` can be merged into `into`, see [SliceAssignments::merge].
+    pub fn check_merge(&self, slice: u16, into: u16) -> Result<(), String> {
+        let len = self.assignments.len();
+        if slice == into || slice as usize >= len || into as usize >= len {
+            return Err(format!("can't merge slice {} into {}", slice, into));
+        }
+        if self.merges.contains_key(&slice) || self.merges.contains_key(&into) {
+            return Err(format!("slice {} or {} is already merged", slice, into));
+        }
+        if self.owner(slice) != self.owner(into) {
+            return Err(format!(
+                "slices {} and {} are on different servers",
+                slice, into
+            ));
+        }
+        let c = &self.constraints;
+        if c.pins.get(&slice) != c.pins.get(&into)
+            || c.forbidden.get(&slice) != c.forbidden.get(&into)
+        {
+            return Err(format!(
+                "slices {} and {} have different constraints",
+                slice, into
+            ));
+        }
+        Ok(())
+    }
+
+    /// Merge `slice` into `into` so they're routed, moved and balanced as one
+    /// slice. They must be on the same server with the same placement
+    /// constraints, so no key changes server. Merging the last slice that was
+    /// split off back into its parent undoes the split and frees up its
+    /// number, other merges are recorded so they can be split again.
+    pub fn merge(&mut self, slice: u16, into: u16, now: i64) -> Result<(), String> {
+        self.check_merge(slice, into)?;
+        let len = self.assignments.len();
+        let last_split = self
+            .splits
+            .get(&into)
+            .and_then(|s| s.children.last())
+            .copied();
+        let unsplit = last_split == Some(slice)
+            && slice as usize == len - 1
+            && self
+                .splits
+                .get(&slice)
+                .is_none_or(|s| s.children.is_empty())
+            && !self.merges.values().any(|m| m.into == slice);
+        if unsplit {
+            self.splits.get_mut(&into).unwrap().children.pop();
+            self.assignments.pop();
+            self.splits.remove(&slice);
+            self.constraints.pins.remove(&slice);
+            self.constraints.forbidden.remove(&slice);
+            self.churn.last_moved.remove(&slice);
         } else {
-            info!("No moves found");
+            for merge in self.merges.values_mut() {
+                if merge.into == slice {
+                    merge.into = into;
+                }
+            }
+            self.merges.insert(slice, Merge { into, at: now });
         }
+        self.splits.entry(into).or_default().at = now;
+        Ok(())
+    }
+
+    /// When `slice` was last split or merged, either as the slice being split
+    /// or merged into, or as the other half.
+    pub fn reshaped_at(&self, slice: u16) -> Option<i64> {
+        let split = self.splits.get(&slice).map(|s| s.at);
+        let created = self
+            .splits
+            .iter()
+            .filter(|(_, s)| s.children.contains(&slice))
+            .map(|(&parent, _)| self.splits[&parent].at)
+            .max();
+        split.max(created)
     }
 
-    pub fn to_backends(&self) -> BTreeSet<Backend> {
+    /// Backends for every server, sharing each server's state from `states`.
+    pub fn to_backends(&self, states: &ServerStates) -> BTreeSet<Backend> {
         let mut backends = BTreeSet::new();
-        for (i, server) in self.servers.iter().enumerate() {
+        for server in self.servers.iter() {
             let mut backend = Backend::new(&server.to_string()).unwrap();
-            let mut slices = BTreeSet::new();
-            for (slice_idx, &server_idx) in self.assignments.iter().enumerate() {
-                if server_idx == i {
-                    slices.insert(slice_idx as u16);
-                }
-            }
-            backend.ext.insert(slices);
-            backend.ext.insert(HealthStatus::new());
+            backend.ext.insert(states.status(*server));
             backends.insert(backend);
         }
         backends
@@ -112,11 +502,13 @@ impl SliceAssignments {
 }
 
 #[derive(Debug, Clone)]
-struct Move {
-    slice_id: u16,
-    from_server: SocketAddr,
-    to_server: SocketAddr,
-    benefit: f32,
+pub struct Move {
+    pub slice_id: u16,
+    pub from_server: SocketAddr,
+    pub to_server: SocketAddr,
+    /// How much the move reduces the imbalance. For the two halves of a swap
+    /// this is the benefit of the whole swap.
+    pub benefit: f32,
 }
 
 impl PartialOrd for Move {
@@ -131,7 +523,7 @@ impl PartialEq for Move {
     }
 }
 
-struct Balance {}
+pub struct Balance {}
 
 impl Balance {
     // Threshold above average load that triggers rebalancing (20%)
@@ -139,88 +531,209 @@ impl Balance {
     // Maximum number of moves per rebalancing cycle
     const MAX_MOVES_PER_CYCLE: usize = 3;
 
-    fn collect_server_stats(
+    pub fn collect_server_stats(
         backends: &BTreeSet<Backend>,
     ) -> (
         HashMap<SocketAddr, u32>,
         HashMap<SocketAddr, HashMap<u16, u32>>,
     ) {
-        let mut servers = HashMap::new();
-        let mut server_slices = HashMap::new();
+        Self::usage_stats(&Self::backend_usage(backends, Duration::ZERO))
+    }
 
+    /// The usage each backend last reported to the health check. Backends
+    /// whose usage is older than `max_age` are left out, so they're neither
+    /// given nor relieved of slices on the strength of it.
+    pub fn backend_usage(
+        backends: &BTreeSet<Backend>,
+        max_age: Duration,
+    ) -> HashMap<SocketAddr, Usage> {
+        let mut usage = HashMap::new();
         for backend in backends {
             let addr = backend.addr.to_socket_addrs().unwrap().next().unwrap();
             let status = backend.ext.get::<HealthStatus>().unwrap();
 
-            if let Some(usage) = status.inner.read().unwrap().usage.clone() {
-                servers.insert(addr, usage.slices.values().map(|v| v.load).sum());
-                let mut slices = HashMap::new();
-                for (slice_id, load) in usage.slices {
-                    slices.insert(slice_id, load.load);
-                }
-                server_slices.insert(addr, slices);
+            if let Some(server_usage) = status.inner.read().unwrap().fresh_usage(max_age) {
+                usage.insert(addr, server_usage.clone());
             }
         }
+        usage
+    }
+
+    /// Total load per server and load per slice, from a snapshot of the usage
+    /// reported by each server. See [crate::resources::slice_loads] for how
+    /// slice load is worked out.
+    pub fn usage_stats(
+        usage: &HashMap<SocketAddr, Usage>,
+    ) -> (
+        HashMap<SocketAddr, u32>,
+        HashMap<SocketAddr, HashMap<u16, u32>>,
+    ) {
+        let server_slices = resources::slice_loads(usage);
+        let servers = server_slices
+            .iter()
+            .map(|(&addr, slices)| (addr, slices.values().sum()))
+            .collect();
 
         (servers, server_slices)
     }
 
-    pub fn find_best_moves(backends: &BTreeSet<Backend>) -> Vec<Move> {
-        let mut moves = Vec::new();
-        let (mut servers, server_slices) = Self::collect_server_stats(backends);
+    /// Moves from the usage attached to `backends` by health checks. Moves
+    /// only go to servers that can take the slice, see
+    /// [SliceAssignments::can_take].
+    pub fn find_best_moves(
+        backends: &BTreeSet<Backend>,
+        placement: &SliceAssignments,
+    ) -> Vec<Move> {
+        let (servers, server_slices) = Self::collect_server_stats(backends);
+        Self::find_moves(servers, &server_slices, placement)
+    }
+
+    /// Like [Balance::find_best_moves] but from stats that have already been
+    /// collected, see [Balance::usage_stats].
+    pub fn find_moves(
+        mut servers: HashMap<SocketAddr, u32>,
+        server_slices: &HashMap<SocketAddr, HashMap<u16, u32>>,
+        placement: &SliceAssignments,
+    ) -> Vec<Move> {
+        let mut moves: Vec<Move> = Vec::new();
+        // Apply moves to copies so later moves account for earlier ones.
+        let mut server_slices = server_slices.clone();
+        let mut placement = placement.clone();
 
         // Calculate threshold for overloaded servers
         let avg_load = servers.values().sum::<u32>() as f32 / servers.len() as f32;
         let threshold = avg_load * Self::OVERLOAD_THRESHOLD;
 
-        let o = servers.clone();
-        // Find and sort overloaded servers
-        let mut overloaded: Vec<_> = o
-            .iter()
-            .filter(|(_, &load)| load as f32 > threshold)
-            .collect();
-        overloaded.sort_by(|a, b| b.1.cmp(a.1));
+        while moves.len() < Self::MAX_MOVES_PER_CYCLE {
+            // Find and sort overloaded servers
+            let mut overloaded: Vec<_> = servers
+                .iter()
+                .filter(|(_, &load)| load as f32 > threshold)
+                .map(|(&addr, &load)| (addr, load))
+                .collect();
+            overloaded.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
 
-        for (&hot_server, _) in overloaded {
-            if moves.len() >= Self::MAX_MOVES_PER_CYCLE {
+            let moved: BTreeSet<_> = moves.iter().map(|m| m.slice_id).collect();
+            let allow_swaps = moves.len() + 2 <= Self::MAX_MOVES_PER_CYCLE;
+            let Some(candidate) = overloaded.iter().find_map(|&(hot, _)| {
+                Self::best_candidate(
+                    hot,
+                    &servers,
+                    &server_slices,
+                    &placement,
+                    &moved,
+                    allow_swaps,
+                )
+            }) else {
                 break;
+            };
+
+            for mov in candidate {
+                let load = server_slices
+                    .get_mut(&mov.from_server)
+                    .unwrap()
+                    .remove(&mov.slice_id)
+                    .unwrap();
+                server_slices
+                    .entry(mov.to_server)
+                    .or_default()
+                    .insert(mov.slice_id, load);
+                *servers.get_mut(&mov.from_server).unwrap() -= load;
+                *servers.get_mut(&mov.to_server).unwrap() += load;
+                let _ = placement.move_slice(mov.slice_id, mov.to_server);
+                moves.push(mov);
             }
+        }
+
+        moves
+    }
 
-            // Find largest slice from hot server
-            if let Some((&largest_slice, &slice_load)) = server_slices
-                .get(&hot_server)
-                .and_then(|slices| slices.iter().max_by_key(|(_, &load)| load))
-            {
-                // Find least loaded target server
-                if let Some((&target_server, _)) = servers
+    /// The move, or swap, of slices off `hot` that most reduces the imbalance.
+    /// Moves that don't reduce it, eg. because the slice is bigger than the
+    /// gap and would make the target the new hottest server, are never
+    /// returned. On a tie a single move is preferred over a swap, and larger
+    /// slices over smaller ones.
+    fn best_candidate(
+        hot: SocketAddr,
+        servers: &HashMap<SocketAddr, u32>,
+        server_slices: &HashMap<SocketAddr, HashMap<u16, u32>>,
+        placement: &SliceAssignments,
+        moved: &BTreeSet<u16>,
+        allow_swaps: bool,
+    ) -> Option<Vec<Move>> {
+        let old_imbalance = Self::calculate_imbalance(servers);
+        // Slices that haven't moved yet this cycle, largest first
+        let slices_of = |server: &SocketAddr| {
+            let mut slices: Vec<_> = server_slices
+                .get(server)
+                .map(|slices| {
+                    slices
+                        .iter()
+                        .filter(|(slice, _)| !moved.contains(slice))
+                        .map(|(&slice, &load)| (slice, load))
+                        .collect()
+                })
+                .unwrap_or_default();
+            slices.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+            slices
+        };
+        let mut targets: Vec<_> = servers.keys().filter(|&&s| s != hot).copied().collect();
+        targets.sort();
+
+        let mut best: Option<(Vec<Move>, f32)> = None;
+        let mut consider = |transfers: &[(u16, SocketAddr, SocketAddr, u32)]| {
+            let mut loads = servers.clone();
+            for &(_, from, to, load) in transfers {
+                *loads.get_mut(&from).unwrap() -= load;
+                *loads.get_mut(&to).unwrap() += load;
+            }
+            let benefit = old_imbalance - Self::calculate_imbalance(&loads);
+            if benefit > 0.0 && best.as_ref().is_none_or(|(_, b)| benefit > *b) {
+                let moves = transfers
                     .iter()
-                    .filter(|(&addr, _)| addr != hot_server)
-                    .min_by_key(|(_, &load)| load)
-                {
-                    let old_imbalance = Self::calculate_imbalance(&servers);
-
-                    // Update server loads
-                    *servers.get_mut(&hot_server).unwrap() -= slice_load;
-                    *servers.get_mut(&target_server).unwrap() += slice_load;
-
-                    moves.push(Move {
-                        slice_id: largest_slice,
-                        from_server: hot_server,
-                        to_server: target_server,
-                        benefit: old_imbalance - Self::calculate_imbalance(&servers),
-                    });
+                    .map(|&(slice_id, from_server, to_server, _)| Move {
+                        slice_id,
+                        from_server,
+                        to_server,
+                        benefit,
+                    })
+                    .collect();
+                best = Some((moves, benefit));
+            }
+        };
+
+        let hot_slices = slices_of(&hot);
+        for &(slice, load) in &hot_slices {
+            for &target in targets.iter().filter(|t| placement.can_take(slice, t)) {
+                consider(&[(slice, hot, target, load)]);
+            }
+        }
+        if allow_swaps {
+            for &(slice, load) in &hot_slices {
+                for &target in targets.iter().filter(|t| placement.can_take(slice, t)) {
+                    for (cold_slice, cold_load) in slices_of(&target) {
+                        if cold_load < load && placement.can_take(cold_slice, &hot) {
+                            consider(&[
+                                (slice, hot, target, load),
+                                (cold_slice, target, hot, cold_load),
+                            ]);
+                        }
+                    }
                 }
             }
         }
-
-        moves
+        best.map(|(moves, _)| moves)
     }
 
-    fn calculate_imbalance(slices: &HashMap<SocketAddr, u32>) -> f32 {
+    /// The highest server load over the mean load, 1 is perfectly balanced.
+    pub fn calculate_imbalance(slices: &HashMap<SocketAddr, u32>) -> f32 {
         let total_load = slices.values().sum::<u32>();
+        if total_load == 0 {
+            return 1.0;
+        }
         let max_load = *slices.values().max().unwrap();
-        let mean_load = total_load / slices.len() as u32;
-        max_load as f32 / mean_load as f32
+        let mean_load = total_load as f32 / slices.len() as f32;
+        max_load as f32 / mean_load
     }
 }
 
@@ -234,19 +747,27 @@ mod tests {
         let mut status = HealthStatus::new();
 
         // Create usage data
-        let mut usage = crate::health_check::Usage {
-            slices: HashMap::new(),
-        };
+        let mut usage = crate::health_check::Usage::default();
         for (slice_id, load) in slice_loads {
-            usage
-                .slices
-                .insert(slice_id, crate::health_check::SliceUsage { load });
+            usage.slices.insert(
+                slice_id,
+                crate::health_check::SliceUsage {
+                    load,
+                    ..Default::default()
+                },
+            );
         }
 
         status.inner = Arc::new(RwLock::new(crate::health_check::HealthStatusInner {
             usage: Some(usage),
             is_healthy: true,
             last_check: std::time::Instant::now(),
+            usage_at: Some(std::time::Instant::now()),
+            received_at: Some(std::time::Instant::now()),
+            smoothed: HashMap::new(),
+            reports: Default::default(),
+            consecutive_successes: 0,
+            consecutive_failures: 0,
         }));
 
         backend.ext.insert(status);
@@ -299,18 +820,236 @@ mod tests {
             vec![(2, 150), (3, 150)],
         ));
 
-        let moves = Balance::find_best_moves(&backends);
+        let placement = SliceAssignments::new(vec![
+            "127.0.0.1:8001".parse().unwrap(),
+            "127.0.0.1:8002".parse().unwrap(),
+        ]);
+        let moves = Balance::find_best_moves(&backends, &placement);
 
         assert!(!moves.is_empty());
         let first_move = &moves[0];
 
-        // The largest slice (500) should be moved from the overloaded to underloaded server
+        // The largest slice (500) should be swapped from the overloaded to underloaded server
         assert_eq!(first_move.slice_id, 1);
         assert_eq!(first_move.from_server, "127.0.0.1:8001".parse().unwrap());
         assert_eq!(first_move.to_server, "127.0.0.1:8002".parse().unwrap());
         assert!(first_move.benefit > 0.0);
     }
 
+    #[test]
+    fn test_update_keeps_remaining_assignments() {
+        let servers: Vec<SocketAddr> = vec![
+            "127.0.0.1:8001".parse().unwrap(),
+            "127.0.0.1:8002".parse().unwrap(),
+            "127.0.0.1:8003".parse().unwrap(),
+        ];
+        let mut assignments = SliceAssignments::new(servers.clone());
+        let before = assignments.clone();
+
+        // Removing a server from the middle of the list shifts the others.
+        assignments.update(vec![servers[0], servers[2]]);
+        for (slice, &server) in before.assignments.iter().enumerate() {
+            let owner = assignments.servers[assignments.assignments[slice]];
+            if server != 1 {
+                assert_eq!(owner, before.servers[server]);
+            } else {
+                assert_ne!(owner, servers[1]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_cordoned_servers_get_no_new_slices() {
+        let servers: Vec<SocketAddr> = vec![
+            "127.0.0.1:8001".parse().unwrap(),
+            "127.0.0.1:8002".parse().unwrap(),
+            "127.0.0.1:8003".parse().unwrap(),
+        ];
+        let mut assignments = SliceAssignments::new(servers.clone());
+        assignments.cordons.insert(servers[0], Cordon::Cordoned);
+        let cordoned_slices = assignments.slice_count(&servers[0]);
+
+        assignments.update(vec![servers[0], servers[1]]);
+        assert_eq!(assignments.slice_count(&servers[0]), cordoned_slices);
+        assert_eq!(
+            assignments.slice_count(&servers[1]),
+            NUM_SLICES as usize - cordoned_slices
+        );
+    }
+
+    #[test]
+    fn test_drain() {
+        let servers: Vec<SocketAddr> = vec![
+            "127.0.0.1:8001".parse().unwrap(),
+            "127.0.0.1:8002".parse().unwrap(),
+            "127.0.0.1:8003".parse().unwrap(),
+        ];
+        let mut assignments = SliceAssignments::new(servers.clone());
+        assignments.cordons.insert(servers[0], Cordon::Draining);
+        assignments.cordons.insert(servers[1], Cordon::Cordoned);
+        let draining_slices = assignments.slice_count(&servers[0]);
+        let cordoned_slices = assignments.slice_count(&servers[1]);
+
+        assert_eq!(assignments.drain(5), 5);
+        assert_eq!(assignments.slice_count(&servers[0]), draining_slices - 5);
+
+        while assignments.drain(5) > 0 {}
+        assert_eq!(assignments.slice_count(&servers[0]), 0);
+        assert_eq!(assignments.slice_count(&servers[1]), cordoned_slices);
+        assert_eq!(
+            assignments.slice_count(&servers[2]),
+            NUM_SLICES as usize - cordoned_slices
+        );
+    }
+
+    #[test]
+    fn test_constraints() {
+        let servers: Vec<SocketAddr> = vec![
+            "127.0.0.1:8001".parse().unwrap(),
+            "127.0.0.1:8002".parse().unwrap(),
+            "127.0.0.1:8003".parse().unwrap(),
+        ];
+        let mut assignments = SliceAssignments::new(servers.clone());
+        let pinned = (0..NUM_SLICES)
+            .find(|&s| assignments.owner(s) != Some(servers[0]))
+            .unwrap();
+        let forbidden = (0..NUM_SLICES)
+            .find(|&s| assignments.owner(s) == Some(servers[1]))
+            .unwrap();
+        assignments.constraints.pins.insert(pinned, servers[0]);
+        assignments
+            .constraints
+            .forbidden
+            .insert(forbidden, BTreeSet::from([servers[1]]));
+        assignments.constraints.max_slices.insert(servers[2], 10);
+
+        assert!(assignments.enforce_constraints().is_empty());
+        assert_eq!(assignments.owner(pinned), Some(servers[0]));
+        assert_ne!(assignments.owner(forbidden), Some(servers[1]));
+        assert_eq!(assignments.slice_count(&servers[2]), 10);
+
+        // Removing the server a slice is pinned to can't be satisfied, and
+        // neither can limits that leave nowhere for slices to go.
+        assignments.constraints.max_slices.clear();
+        assignments.update(vec![servers[1], servers[2]]);
+        assert_eq!(assignments.owner(forbidden), Some(servers[2]));
+        assert_eq!(
+            assignments.violations(),
+            vec![Violation::PinnedServerMissing {
+                slice: pinned,
+                server: servers[0]
+            }]
+        );
+        assignments.constraints.max_slices.insert(servers[1], 10);
+        assignments.constraints.max_slices.insert(servers[2], 10);
+        let violations = assignments.enforce_constraints();
+        assert!(violations
+            .iter()
+            .any(|v| matches!(v, Violation::OverLimit { max: 10, .. })));
+    }
+
+    #[test]
+    fn test_pin_conflicts() {
+        let servers: Vec<SocketAddr> = vec![
+            "127.0.0.1:8001".parse().unwrap(),
+            "127.0.0.1:8002".parse().unwrap(),
+        ];
+        let mut constraints = Constraints::default();
+        constraints.pins.insert(0, servers[0]);
+        constraints.pins.insert(1, servers[0]);
+        constraints.max_slices.insert(servers[0], 1);
+        let mut assignments = SliceAssignments::with_constraints(servers.clone(), constraints);
+        assert_eq!(assignments.owner(0), Some(servers[0]));
+        assert_eq!(assignments.owner(1), Some(servers[0]));
+        assert!(assignments
+            .violations()
+            .contains(&Violation::PinnedOverLimit {
+                server: servers[0],
+                pinned: 2,
+                max: 1
+            }));
+
+        assignments.cordons.insert(servers[0], Cordon::Cordoned);
+        assert!(assignments
+            .enforce_constraints()
+            .contains(&Violation::PinnedServerCordoned {
+                slice: 0,
+                server: servers[0]
+            }));
+        assert_eq!(assignments.owner(0), Some(servers[0]));
+    }
+
+    #[test]
+    fn test_balance_respects_constraints() {
+        let mut backends = BTreeSet::new();
+        backends.insert(create_test_backend(
+            "127.0.0.1:8001",
+            vec![(0, 400), (1, 500)],
+        ));
+        backends.insert(create_test_backend(
+            "127.0.0.1:8002",
+            vec![(2, 150), (3, 150)],
+        ));
+        let mut placement = SliceAssignments::new(vec![
+            "127.0.0.1:8001".parse().unwrap(),
+            "127.0.0.1:8002".parse().unwrap(),
+        ]);
+        placement
+            .constraints
+            .pins
+            .insert(1, "127.0.0.1:8001".parse().unwrap());
+
+        // The largest slice is pinned, so the next largest moves instead.
+        let moves = Balance::find_best_moves(&backends, &placement);
+        assert_eq!(moves[0].slice_id, 0);
+    }
+
+    #[test]
+    fn test_no_overshoot() {
+        let mut backends = BTreeSet::new();
+        backends.insert(create_test_backend(
+            "127.0.0.1:8001",
+            vec![(0, 600), (1, 100)],
+        ));
+        backends.insert(create_test_backend("127.0.0.1:8002", vec![(2, 300)]));
+        let placement = SliceAssignments::new(vec![
+            "127.0.0.1:8001".parse().unwrap(),
+            "127.0.0.1:8002".parse().unwrap(),
+        ]);
+
+        // Moving the 600 slice would just make 8002 the hot server, the
+        // smaller slice fits the gap.
+        let moves = Balance::find_best_moves(&backends, &placement);
+        assert_eq!(moves.len(), 1);
+        assert_eq!(moves[0].slice_id, 1);
+        assert!(moves.iter().all(|m| m.benefit > 0.0));
+    }
+
+    #[test]
+    fn test_swap() {
+        let mut backends = BTreeSet::new();
+        backends.insert(create_test_backend(
+            "127.0.0.1:8001",
+            vec![(0, 400), (1, 400)],
+        ));
+        backends.insert(create_test_backend(
+            "127.0.0.1:8002",
+            vec![(2, 300), (3, 100)],
+        ));
+        let placement = SliceAssignments::new(vec![
+            "127.0.0.1:8001".parse().unwrap(),
+            "127.0.0.1:8002".parse().unwrap(),
+        ]);
+
+        // No single move helps, swapping a 400 slice for the 300 one does.
+        let moves = Balance::find_best_moves(&backends, &placement);
+        let moves: Vec<_> = moves
+            .iter()
+            .map(|m| (m.slice_id, m.to_server.port()))
+            .collect();
+        assert_eq!(moves, vec![(0, 8002), (2, 8001)]);
+    }
+
     #[test]
     fn test_calculate_imbalance() {
         let mut servers = HashMap::new();
@@ -343,7 +1082,12 @@ mod tests {
             vec![(4, 100), (5, 100)],
         ));
 
-        let moves = Balance::find_best_moves(&backends);
+        let placement = SliceAssignments::new(vec![
+            "127.0.0.1:8001".parse().unwrap(),
+            "127.0.0.1:8002".parse().unwrap(),
+            "127.0.0.1:8003".parse().unwrap(),
+        ]);
+        let moves = Balance::find_best_moves(&backends, &placement);
         println!("moves: {:?}", moves);
         assert!(moves.is_empty());
     }
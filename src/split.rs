@@ -0,0 +1,425 @@
+use crate::health_check::Usage;
+use crate::resources;
+use crate::slice_assignments::SliceAssignments;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+/// When to split a hot slice so its keys can be spread over more than one
+/// server, and when to merge cold slices to keep the number of slices down.
+#[derive(Debug, Clone)]
+pub struct SplitSettings {
+    /// Slices with more load than this fraction of a server's capacity are
+    /// split, see [resources::server_capacity]. Zero never splits.
+    pub max_slice_fraction: f64,
+    /// The most slices there can be, including split off ones.
+    pub max_slices: usize,
+    /// How long after a split before either half can be split again, so
+    /// load reported from before the split doesn't split it straight away.
+    pub cooldown: Duration,
+    /// Slices on the same server with less load than this fraction of a
+    /// server's capacity between them are merged. Zero never merges.
+    pub min_slice_fraction: f64,
+    /// How long slices have to stay cold before they're merged.
+    pub cold_for: Duration,
+}
+
+impl Default for SplitSettings {
+    fn default() -> Self {
+        Self {
+            max_slice_fraction: 0.0,
+            max_slices: 1000,
+            cooldown: Duration::from_secs(300),
+            min_slice_fraction: 0.0,
+            cold_for: Duration::from_secs(600),
+        }
+    }
+}
+
+/// Whether `slice` was split or merged within the cooldown.
+fn cooling_down(assignments: &SliceAssignments, slice: u16, now: i64, cooldown: Duration) -> bool {
+    assignments
+        .reshaped_at(slice)
+        .is_some_and(|at| now - at < cooldown.as_millis() as i64)
+}
+
+/// Whether any slice split off `slice` has no load yet, in which case the
+/// load of `slice` may still include theirs.
+fn children_unreported(
+    assignments: &SliceAssignments,
+    loads: &HashMap<SocketAddr, HashMap<u16, u32>>,
+    slice: u16,
+) -> bool {
+    assignments.splits.get(&slice).is_some_and(|split| {
+        split
+            .children
+            .iter()
+            .any(|child| !loads.values().any(|slices| slices.contains_key(child)))
+    })
+}
+
+/// The slices to split, hottest first. Slices that were split before aren't
+/// split again until the split off slices report load of their own.
+pub fn hot_slices(
+    assignments: &SliceAssignments,
+    usage: &HashMap<SocketAddr, Usage>,
+    now: i64,
+    settings: &SplitSettings,
+) -> Vec<u16> {
+    if settings.max_slice_fraction <= 0.0 {
+        return Vec::new();
+    }
+    let threshold = resources::server_capacity(usage) * settings.max_slice_fraction;
+    let loads = resources::slice_loads(usage);
+    let mut hot: Vec<(u16, u32)> = loads
+        .iter()
+        .flat_map(|(&server, slices)| {
+            slices
+                .iter()
+                .map(|(&slice, &load)| (slice, load))
+                .filter(move |&(slice, _)| assignments.owner(slice) == Some(server))
+        })
+        .filter(|&(slice, load)| {
+            load as f64 > threshold
+                && !cooling_down(assignments, slice, now, settings.cooldown)
+                && !children_unreported(assignments, &loads, slice)
+        })
+        .collect();
+    hot.sort_by_key(|&(slice, load)| (std::cmp::Reverse(load), slice));
+    let room = settings
+        .max_slices
+        .saturating_sub(assignments.assignments.len());
+    hot.into_iter().take(room).map(|(slice, _)| slice).collect()
+}
+
+/// Keeps track of how long slices have been cold for, to find slices to
+/// merge.
+#[derive(Debug, Default)]
+pub struct ColdSlices {
+    /// When each slice was first seen cold, milliseconds since the epoch.
+    since: HashMap<u16, i64>,
+}
+
+impl ColdSlices {
+    /// Slices to merge as `(slice, into)`, given the usage now. Undoing a
+    /// split is preferred, otherwise each slice on a server, coldest first,
+    /// is paired with the coldest one it can be merged with. Merged slices
+    /// stay cold.
+    pub fn merges(
+        &mut self,
+        assignments: &SliceAssignments,
+        usage: &HashMap<SocketAddr, Usage>,
+        now: i64,
+        settings: &SplitSettings,
+    ) -> Vec<(u16, u16)> {
+        if settings.min_slice_fraction <= 0.0 {
+            self.since.clear();
+            return Vec::new();
+        }
+        let threshold = resources::server_capacity(usage) * settings.min_slice_fraction;
+        let loads = resources::slice_loads(usage);
+
+        // Slices that don't report any load on servers that do are cold too.
+        let mut cold: BTreeMap<u16, u32> = BTreeMap::new();
+        for (server, slices) in &loads {
+            for slice in assignments.slices() {
+                let load = slices.get(&slice).copied().unwrap_or(0);
+                if assignments.owner(slice) == Some(*server)
+                    && load as f64 <= threshold
+                    && !cooling_down(assignments, slice, now, settings.cooldown)
+                {
+                    cold.insert(slice, load);
+                }
+            }
+        }
+        self.since.retain(|slice, _| cold.contains_key(slice));
+        for &slice in cold.keys() {
+            self.since.entry(slice).or_insert(now);
+        }
+        let cold_for = settings.cold_for.as_millis() as i64;
+        cold.retain(|slice, _| now - self.since[slice] >= cold_for);
+
+        let mut merges = Vec::new();
+        let mut merge = |slice: u16, into: u16, cold: &mut BTreeMap<u16, u32>| {
+            let (Some(&a), Some(&b)) = (cold.get(&slice), cold.get(&into)) else {
+                return false;
+            };
+            if (a + b) as f64 > threshold || assignments.check_merge(slice, into).is_err() {
+                return false;
+            }
+            cold.remove(&slice);
+            cold.remove(&into);
+            merges.push((slice, into));
+            true
+        };
+        for (&parent, split) in assignments.splits.iter().rev() {
+            if let Some(&child) = split.children.last() {
+                merge(child, parent, &mut cold);
+            }
+        }
+        let mut by_server: BTreeMap<SocketAddr, Vec<(u32, u16)>> = BTreeMap::new();
+        for (&slice, &load) in &cold {
+            by_server
+                .entry(assignments.owner(slice).unwrap())
+                .or_default()
+                .push((load, slice));
+        }
+        for mut slices in by_server.into_values() {
+            slices.sort();
+            while !slices.is_empty() {
+                let (_, slice) = slices.remove(0);
+                // Leftovers that can't be merged with this one get another go
+                // with the next.
+                let paired = slices
+                    .iter()
+                    .position(|&(_, other)| merge(slice.max(other), slice.min(other), &mut cold));
+                if let Some(i) = paired {
+                    slices.remove(i);
+                }
+            }
+        }
+        for (slice, into) in &merges {
+            self.since.remove(slice);
+            self.since.remove(into);
+        }
+        merges
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::health_check::SliceUsage;
+    use crate::slice_assignments::NUM_SLICES;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn test_split_routing() {
+        let mut assignments = SliceAssignments::new(vec![addr(1), addr(2)]);
+        let keys: Vec<String> = (0..2000).map(|i| format!("user-{}", i)).collect();
+        let before: Vec<u16> = keys
+            .iter()
+            .map(|k| assignments.slice_for_key(k.as_bytes()))
+            .collect();
+        let hot = before[0];
+
+        let child = assignments.split(hot, 0).unwrap();
+        assert_eq!(child, NUM_SLICES);
+        assert_eq!(assignments.owner(child), assignments.owner(hot));
+        let grandchild = assignments.split(child, 0).unwrap();
+
+        // Only keys on the split slice move, about half of them each time.
+        let mut counts: HashMap<u16, usize> = HashMap::new();
+        for (key, &slice) in keys.iter().zip(&before) {
+            let after = assignments.slice_for_key(key.as_bytes());
+            if slice != hot {
+                assert_eq!(after, slice);
+            } else {
+                *counts.entry(after).or_default() += 1;
+            }
+        }
+        let total: usize = counts.values().sum();
+        assert!(counts[&hot] * 4 > total && counts[&hot] * 4 < total * 3);
+        assert!(counts[&child] > 0 && counts[&grandchild] > 0);
+
+        // Splits survive being persisted.
+        let json = serde_json::to_string(&assignments).unwrap();
+        let read: SliceAssignments = serde_json::from_str(&json).unwrap();
+        assert_eq!(
+            read.slice_for_key(keys[0].as_bytes()),
+            assignments.slice_for_key(keys[0].as_bytes())
+        );
+        assert_eq!(read.slices().count(), NUM_SLICES as usize + 2);
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut assignments = SliceAssignments::new(vec![addr(1), addr(2)]);
+        let keys: Vec<String> = (0..2000).map(|i| format!("user-{}", i)).collect();
+        let owners = |assignments: &SliceAssignments| -> Vec<_> {
+            keys.iter()
+                .map(|k| assignments.owner(assignments.slice_for_key(k.as_bytes())))
+                .collect()
+        };
+        let before = owners(&assignments);
+        let slices: Vec<_> = assignments
+            .slices()
+            .filter(|&s| assignments.owner(s) == Some(addr(1)))
+            .take(3)
+            .collect();
+        let server = assignments.owner(slices[0]).unwrap();
+        assert!(assignments.merge(slices[0], slices[0], 0).is_err());
+
+        // Merged slices route together, and move together.
+        assignments.merge(slices[1], slices[0], 0).unwrap();
+        assignments.merge(slices[2], slices[1], 0).unwrap_err();
+        assignments.merge(slices[2], slices[0], 0).unwrap();
+        assert_eq!(owners(&assignments), before);
+        assert_eq!(assignments.slices().count(), NUM_SLICES as usize - 2);
+        assert_eq!(assignments.root(slices[2]), slices[0]);
+        let other = if server == addr(1) { addr(2) } else { addr(1) };
+        assignments.move_slice(slices[2], other).unwrap();
+        assert!(slices.iter().all(|&s| assignments.owner(s) == Some(other)));
+        assignments.move_slice(slices[0], server).unwrap();
+
+        // Splitting undoes the latest merge.
+        assert_eq!(assignments.split(slices[0], 0).unwrap(), slices[2]);
+        assert_eq!(assignments.owner(slices[2]), Some(server));
+        assert_eq!(owners(&assignments), before);
+
+        // Merging a split off slice back into its parent undoes the split.
+        let child = assignments.split(slices[2], 0).unwrap();
+        assignments.merge(child, slices[2], 0).unwrap();
+        assert_eq!(assignments.assignments.len(), NUM_SLICES as usize);
+        assert!(assignments.splits[&slices[2]].children.is_empty());
+        assert_eq!(owners(&assignments), before);
+    }
+
+    #[test]
+    fn test_cold_slices() {
+        let mut assignments = SliceAssignments::new(vec![addr(1), addr(2)]);
+        let server = assignments.owner(0).unwrap();
+        let child = assignments.split(0, 0).unwrap();
+        let settings = SplitSettings {
+            min_slice_fraction: 0.01,
+            cold_for: Duration::from_secs(60),
+            cooldown: Duration::from_secs(60),
+            ..Default::default()
+        };
+        let owned: Vec<_> = assignments
+            .slices()
+            .filter(|&s| assignments.owner(s) == Some(server))
+            .collect();
+        let slices = owned
+            .iter()
+            .map(|&s| {
+                let load = if s == 0 || s == child { 1 } else { 50 };
+                (
+                    s,
+                    SliceUsage {
+                        load,
+                        ..Default::default()
+                    },
+                )
+            })
+            .collect();
+        let usage = HashMap::from([(
+            server,
+            Usage {
+                slices,
+                ..Default::default()
+            },
+        )]);
+
+        // Slices with 50 are too warm to merge with anything.
+        let mut cold = ColdSlices::default();
+        // Still cooling down from the split, then not cold for long enough.
+        assert!(cold
+            .merges(&assignments, &usage, 30_000, &settings)
+            .is_empty());
+        assert!(cold
+            .merges(&assignments, &usage, 60_000, &settings)
+            .is_empty());
+        assert_eq!(
+            cold.merges(&assignments, &usage, 120_000, &settings),
+            vec![(child, 0)]
+        );
+    }
+
+    #[test]
+    fn test_merge_pairing() {
+        let mut assignments = SliceAssignments::new(vec![addr(1), addr(2)]);
+        let owned: Vec<_> = assignments
+            .slices()
+            .filter(|&s| assignments.owner(s) == Some(addr(1)))
+            .collect();
+        let (a, b, c) = (owned[0], owned[1], owned[2]);
+        // The first slice can't be merged with either of the others.
+        assignments.constraints.pins.insert(a, addr(1));
+        let slices = owned
+            .iter()
+            .map(|&s| {
+                let load = if [a, b, c].contains(&s) { 1 } else { 50 };
+                (
+                    s,
+                    SliceUsage {
+                        load,
+                        ..Default::default()
+                    },
+                )
+            })
+            .collect();
+        let usage = HashMap::from([(
+            addr(1),
+            Usage {
+                slices,
+                ..Default::default()
+            },
+        )]);
+        let settings = SplitSettings {
+            min_slice_fraction: 0.01,
+            cold_for: Duration::ZERO,
+            ..Default::default()
+        };
+        assert_eq!(
+            ColdSlices::default().merges(&assignments, &usage, 0, &settings),
+            vec![(c, b)]
+        );
+    }
+
+    #[test]
+    fn test_hot_slices() {
+        let assignments = SliceAssignments::new(vec![addr(1), addr(2)]);
+        let owned = |server| {
+            assignments
+                .slices()
+                .filter(|&s| assignments.owner(s) == Some(server))
+                .collect::<Vec<_>>()
+        };
+        let (a, b) = (owned(addr(1)), owned(addr(2)));
+        let slice = |load| SliceUsage {
+            load,
+            ..Default::default()
+        };
+        let usage = HashMap::from([
+            (
+                addr(1),
+                Usage {
+                    slices: HashMap::from([(a[0], slice(90)), (a[1], slice(10))]),
+                    ..Default::default()
+                },
+            ),
+            (
+                addr(2),
+                Usage {
+                    slices: HashMap::from([(b[0], slice(50)), (b[1], slice(50))]),
+                    ..Default::default()
+                },
+            ),
+        ]);
+        let settings = SplitSettings {
+            max_slice_fraction: 0.5,
+            ..Default::default()
+        };
+        // Servers average 100, so only the slice with 90 is over 50.
+        assert_eq!(hot_slices(&assignments, &usage, 0, &settings), vec![a[0]]);
+
+        let mut split = assignments.clone();
+        let child = split.split(a[0], 0).unwrap();
+        assert!(hot_slices(&split, &usage, 1_000, &settings).is_empty());
+        // Not until the split off slice reports its own load.
+        assert!(hot_slices(&split, &usage, 300_000, &settings).is_empty());
+        let mut usage = usage;
+        usage
+            .get_mut(&addr(1))
+            .unwrap()
+            .slices
+            .insert(child, slice(0));
+        assert_eq!(hot_slices(&split, &usage, 300_000, &settings), vec![a[0]]);
+        assert!(hot_slices(&assignments, &usage, 0, &SplitSettings::default()).is_empty());
+    }
+}
@@ -0,0 +1,362 @@
+use crate::health_check::SliceUsage;
+use crate::health_check::Usage;
+use crate::selection::SliceSelection;
+use crate::slice_assignments::Balance;
+use crate::slice_assignments::SliceAssignments;
+use pingora_load_balancing::LoadBalancer;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+/// Traffic in one second of the window.
+#[derive(Debug, Default)]
+struct Bucket {
+    second: u64,
+    requests: u64,
+    bytes: u64,
+    errors: u64,
+    latency_ms: u64,
+    latencies: u64,
+}
+
+#[derive(Debug, Default)]
+struct SliceTraffic {
+    /// Oldest first, seconds with no traffic have no bucket.
+    buckets: VecDeque<Bucket>,
+    in_flight: u32,
+}
+
+/// Traffic to a slice over the window, as seen by this load balancer.
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct SliceStats {
+    pub requests_per_sec: f64,
+    pub bytes_per_sec: f64,
+    pub in_flight: u32,
+    /// Fraction of requests that failed or got a 5xx.
+    pub error_rate: f64,
+    /// Mean time for the upstream to respond.
+    pub latency_ms: f64,
+}
+
+/// Per slice request rate, bytes, in-flight requests, errors and upstream
+/// latency measured by the proxy over a sliding window. Each load balancer
+/// only sees its own traffic. Slices are keyed by slice rather than server, so
+/// a slice's history follows it when it moves.
+#[derive(Clone)]
+pub struct TrafficStats {
+    slices: Arc<Mutex<HashMap<u16, SliceTraffic>>>,
+    window: Duration,
+    epoch: Instant,
+}
+
+impl TrafficStats {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            slices: Arc::new(Mutex::new(HashMap::new())),
+            window: window.max(Duration::from_secs(1)),
+            epoch: Instant::now(),
+        }
+    }
+
+    /// A request for `slice` was sent upstream.
+    pub fn start(&self, slice: u16) {
+        self.slices
+            .lock()
+            .unwrap()
+            .entry(slice)
+            .or_default()
+            .in_flight += 1;
+    }
+
+    /// A request started with [TrafficStats::start] finished. `latency` is
+    /// how long the upstream took to respond, if it did.
+    pub fn finish(&self, slice: u16, bytes: usize, error: bool, latency: Option<Duration>) {
+        self.finish_at(slice, bytes, error, latency, Instant::now());
+    }
+
+    fn finish_at(
+        &self,
+        slice: u16,
+        bytes: usize,
+        error: bool,
+        latency: Option<Duration>,
+        now: Instant,
+    ) {
+        let second = now.duration_since(self.epoch).as_secs();
+        let window = self.window.as_secs();
+        let mut slices = self.slices.lock().unwrap();
+        let traffic = slices.entry(slice).or_default();
+        traffic.in_flight = traffic.in_flight.saturating_sub(1);
+        while traffic
+            .buckets
+            .front()
+            .is_some_and(|b| b.second + window <= second)
+        {
+            traffic.buckets.pop_front();
+        }
+        if traffic.buckets.back().map(|b| b.second) != Some(second) {
+            traffic.buckets.push_back(Bucket {
+                second,
+                ..Default::default()
+            });
+        }
+        let bucket = traffic.buckets.back_mut().unwrap();
+        bucket.requests += 1;
+        bucket.bytes += bytes as u64;
+        bucket.errors += error as u64;
+        if let Some(latency) = latency {
+            bucket.latency_ms += latency.as_millis() as u64;
+            bucket.latencies += 1;
+        }
+    }
+
+    /// Stats for every slice with traffic in the window or requests in flight.
+    pub fn stats(&self) -> BTreeMap<u16, SliceStats> {
+        self.stats_at(Instant::now())
+    }
+
+    fn stats_at(&self, now: Instant) -> BTreeMap<u16, SliceStats> {
+        let second = now.duration_since(self.epoch).as_secs();
+        let window = self.window.as_secs();
+        // Until the window has filled up, rates are over the time so far.
+        let elapsed = (second + 1).min(window) as f64;
+        let slices = self.slices.lock().unwrap();
+        slices
+            .iter()
+            .filter_map(|(&slice, traffic)| {
+                let mut total = Bucket::default();
+                for bucket in traffic
+                    .buckets
+                    .iter()
+                    .filter(|b| b.second + window > second)
+                {
+                    total.requests += bucket.requests;
+                    total.bytes += bucket.bytes;
+                    total.errors += bucket.errors;
+                    total.latency_ms += bucket.latency_ms;
+                    total.latencies += bucket.latencies;
+                }
+                if total.requests == 0 && traffic.in_flight == 0 {
+                    return None;
+                }
+                let stats = SliceStats {
+                    requests_per_sec: total.requests as f64 / elapsed,
+                    bytes_per_sec: total.bytes as f64 / elapsed,
+                    in_flight: traffic.in_flight,
+                    error_rate: total.errors as f64 / total.requests.max(1) as f64,
+                    latency_ms: total.latency_ms as f64 / total.latencies.max(1) as f64,
+                };
+                Some((slice, stats))
+            })
+            .collect()
+    }
+
+    /// The traffic to the slices each server owns, see [traffic_usage].
+    pub fn usage(&self, assignments: &SliceAssignments) -> HashMap<SocketAddr, Usage> {
+        traffic_usage(self.stats(), assignments)
+    }
+}
+
+/// Traffic stats in the same form as the usage workers report. `load` is
+/// requests per minute, and the stats that add up across slices are also
+/// resources so they can be balanced on given a capacity, see
+/// [crate::resources]. Error rate and latency are symptoms rather than
+/// something a server runs out of, so they're left out.
+fn traffic_usage(
+    stats: BTreeMap<u16, SliceStats>,
+    assignments: &SliceAssignments,
+) -> HashMap<SocketAddr, Usage> {
+    let mut usage: HashMap<SocketAddr, Usage> = assignments
+        .servers
+        .iter()
+        .map(|&server| (server, Usage::default()))
+        .collect();
+    for (slice, stats) in stats {
+        let Some(server) = assignments.owner(slice) else {
+            continue;
+        };
+        let slice_usage = SliceUsage {
+            load: (stats.requests_per_sec * 60.0).round() as u32,
+            resources: BTreeMap::from([
+                ("requests_per_sec".to_string(), stats.requests_per_sec),
+                ("bytes_per_sec".to_string(), stats.bytes_per_sec),
+                ("in_flight".to_string(), stats.in_flight as f64),
+            ]),
+        };
+        usage
+            .entry(server)
+            .or_default()
+            .slices
+            .insert(slice, slice_usage);
+    }
+    usage
+}
+
+/// Where the rebalancer gets slice load from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LoadSource {
+    /// The usage workers report to the health check.
+    Reported,
+    /// The traffic this load balancer sees, see [TrafficStats].
+    Traffic,
+    /// Reported usage, with traffic filling in for servers and slices that
+    /// don't report any, and traffic stats added as extra resources.
+    Combined,
+}
+
+impl FromStr for LoadSource {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reported" => Ok(LoadSource::Reported),
+            "traffic" => Ok(LoadSource::Traffic),
+            "combined" => Ok(LoadSource::Combined),
+            _ => Err(format!("unknown load source: {}", s)),
+        }
+    }
+}
+
+/// Merge reported usage and traffic usage, see [LoadSource::Combined].
+fn combine(
+    mut reported: HashMap<SocketAddr, Usage>,
+    traffic: HashMap<SocketAddr, Usage>,
+) -> HashMap<SocketAddr, Usage> {
+    for (server, traffic) in traffic {
+        let usage = reported.entry(server).or_default();
+        for (slice, traffic) in traffic.slices {
+            match usage.slices.get_mut(&slice) {
+                Some(slice_usage) => {
+                    for (name, amount) in traffic.resources {
+                        slice_usage.resources.entry(name).or_insert(amount);
+                    }
+                }
+                None => {
+                    usage.slices.insert(slice, traffic);
+                }
+            }
+        }
+    }
+    reported
+}
+
+/// The usage to balance with.
+#[derive(Clone)]
+pub struct UsageSource {
+    pub source: LoadSource,
+    pub traffic: TrafficStats,
+    /// Reported usage older than this is ignored, zero keeps it forever.
+    pub max_age: Duration,
+}
+
+impl Default for UsageSource {
+    fn default() -> Self {
+        Self {
+            source: LoadSource::Reported,
+            traffic: TrafficStats::new(Duration::from_secs(60)),
+            max_age: Duration::from_secs(30),
+        }
+    }
+}
+
+impl UsageSource {
+    pub fn usage(
+        &self,
+        upstreams: &LoadBalancer<SliceSelection>,
+        assignments: &SliceAssignments,
+    ) -> HashMap<SocketAddr, Usage> {
+        let reported = || Balance::backend_usage(&upstreams.backends().get_backend(), self.max_age);
+        match self.source {
+            LoadSource::Reported => reported(),
+            LoadSource::Traffic => self.traffic.usage(assignments),
+            LoadSource::Combined => combine(reported(), self.traffic.usage(assignments)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn test_sliding_window() {
+        let traffic = TrafficStats::new(Duration::from_secs(10));
+        let at = |secs| traffic.epoch + Duration::from_secs(secs);
+        for second in 0..10 {
+            traffic.start(3);
+            traffic.finish_at(
+                3,
+                100,
+                second == 0,
+                Some(Duration::from_millis(20)),
+                at(second),
+            );
+        }
+        traffic.start(3);
+
+        let stats = &traffic.stats_at(at(9))[&3];
+        assert_eq!(stats.requests_per_sec, 1.0);
+        assert_eq!(stats.bytes_per_sec, 100.0);
+        assert_eq!(stats.in_flight, 1);
+        assert_eq!(stats.error_rate, 0.1);
+        assert_eq!(stats.latency_ms, 20.0);
+
+        // Older seconds fall out of the window.
+        let stats = &traffic.stats_at(at(14))[&3];
+        assert_eq!(stats.requests_per_sec, 0.5);
+        assert_eq!(stats.error_rate, 0.0);
+
+        traffic.finish_at(3, 0, false, None, at(30));
+        let stats = &traffic.stats_at(at(45));
+        assert!(stats.is_empty());
+    }
+
+    #[test]
+    fn test_usage() {
+        let assignments = SliceAssignments::new(vec![addr(1), addr(2)]);
+        let slice = (0..)
+            .find(|&s| assignments.owner(s) == Some(addr(1)))
+            .unwrap();
+        let traffic = TrafficStats::new(Duration::from_secs(1));
+        traffic.start(slice);
+        traffic.finish_at(slice, 10, false, None, traffic.epoch);
+
+        let usage = traffic_usage(traffic.stats_at(traffic.epoch), &assignments);
+        assert_eq!(usage[&addr(1)].slices[&slice].load, 60);
+        let resources = &usage[&addr(1)].slices[&slice].resources;
+        assert!(!resources.contains_key("error_rate") && !resources.contains_key("latency_ms"));
+        assert!(usage[&addr(2)].slices.is_empty());
+
+        // Reported usage wins where there is some.
+        let reported = HashMap::from([(
+            addr(1),
+            Usage {
+                slices: HashMap::from([(
+                    slice,
+                    SliceUsage {
+                        load: 7,
+                        ..Default::default()
+                    },
+                )]),
+                ..Default::default()
+            },
+        )]);
+        let combined = combine(reported, usage);
+        assert_eq!(combined[&addr(1)].slices[&slice].load, 7);
+        assert_eq!(
+            combined[&addr(1)].slices[&slice].resources["requests_per_sec"],
+            1.0
+        );
+        assert!(combined.contains_key(&addr(2)));
+    }
+}
@@ -0,0 +1,403 @@
+use pingora_core::protocols::tls::CaType;
+use pingora_core::tls::pkey::PKey;
+use pingora_core::tls::pkey::Private;
+use pingora_core::tls::x509::X509;
+use pingora_core::upstreams::peer::HttpPeer;
+use pingora_core::upstreams::peer::Scheme;
+use pingora_core::utils::tls::CertKey;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// How to talk TLS to workers, both for proxied requests and health checks,
+/// eg:
+///
+/// ```json
+/// {"enabled": true, "sni": "workers.internal", "ca_file": "/etc/sliced/ca.pem",
+///  "cert_file": "/etc/sliced/client.pem", "key_file": "/etc/sliced/client.key"}
+/// ```
+///
+/// TLS itself is done by pingora with OpenSSL, see [UpstreamTls].
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default)]
+pub struct UpstreamTlsConfig {
+    pub enabled: bool,
+    /// The name workers' certificates are checked against. Needed when
+    /// `verify_cert` is on, as pingora doesn't verify connections without an
+    /// SNI. Health checks default to their host.
+    pub sni: Option<String>,
+    /// CA bundle to verify workers' certificates against, instead of the
+    /// system's.
+    pub ca_file: Option<PathBuf>,
+    /// Client certificate and key, for workers that require them. The
+    /// certificate file can also hold intermediates, after the leaf.
+    pub cert_file: Option<PathBuf>,
+    pub key_file: Option<PathBuf>,
+    pub verify_cert: bool,
+    pub verify_hostname: bool,
+    /// Also accept certificates for this name, eg when workers share a
+    /// certificate.
+    pub alternative_cn: Option<String>,
+}
+
+impl Default for UpstreamTlsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            sni: None,
+            ca_file: None,
+            cert_file: None,
+            key_file: None,
+            verify_cert: true,
+            verify_hostname: true,
+            alternative_cn: None,
+        }
+    }
+}
+
+/// An [UpstreamTlsConfig] with its certificates loaded, ready to set up
+/// connections to workers.
+#[derive(Clone, Default)]
+pub struct UpstreamTls {
+    config: UpstreamTlsConfig,
+    ca: Option<Arc<CaType>>,
+    client_cert_key: Option<Arc<CertKey>>,
+}
+
+impl UpstreamTls {
+    /// Load the certificates in `config`, so mistakes show up at startup
+    /// rather than on the first connection.
+    pub fn from_config(config: &UpstreamTlsConfig) -> Result<Self, String> {
+        let mut tls = Self {
+            config: config.clone(),
+            ..Default::default()
+        };
+        if !config.enabled {
+            return Ok(tls);
+        }
+        if config.verify_cert && config.sni.is_none() {
+            return Err(
+                "sni has to be set to verify workers' certificates, pingora skips verification without one"
+                    .to_string(),
+            );
+        }
+        if let Some(path) = &config.ca_file {
+            tls.ca = Some(Arc::new(read_certificates(path)?.into_boxed_slice()));
+        }
+        tls.client_cert_key = match (&config.cert_file, &config.key_file) {
+            (Some(cert), Some(key)) => Some(Arc::new(CertKey::new(
+                read_certificates(cert)?,
+                read_key(key)?,
+            ))),
+            (None, None) => None,
+            _ => return Err("cert_file and key_file have to be given together".to_string()),
+        };
+        Ok(tls)
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.config.enabled
+    }
+
+    /// Set up `peer` to connect with TLS, if it's enabled.
+    pub fn apply(&self, peer: &mut HttpPeer) {
+        if !self.config.enabled {
+            return;
+        }
+        peer.scheme = Scheme::HTTPS;
+        if let Some(sni) = &self.config.sni {
+            peer.sni = sni.clone();
+        }
+        peer.options.verify_cert = self.config.verify_cert;
+        peer.options.verify_hostname = self.config.verify_hostname;
+        peer.options.alternative_cn = self.config.alternative_cn.clone();
+        peer.options.ca = self.ca.clone();
+        peer.client_cert_key = self.client_cert_key.clone();
+    }
+}
+
+/// Read a PEM file, checking it has at least one `label` block in it.
+fn read_pem(path: &Path, label: &str) -> Result<String, String> {
+    let pem = std::fs::read_to_string(path)
+        .map_err(|e| format!("can't read {}: {}", path.display(), e))?;
+    let has_block = pem
+        .lines()
+        .any(|line| line.starts_with("-----BEGIN ") && line.ends_with(&format!("{}-----", label)));
+    if !has_block {
+        return Err(format!("{} has no {} in it", path.display(), label));
+    }
+    Ok(pem)
+}
+
+fn read_certificates(path: &Path) -> Result<Vec<X509>, String> {
+    let pem = read_pem(path, "CERTIFICATE")?;
+    X509::stack_from_pem(pem.as_bytes())
+        .map_err(|e| format!("can't parse {}: {}", path.display(), e))
+}
+
+fn read_key(path: &Path) -> Result<PKey<Private>, String> {
+    let pem = read_pem(path, "PRIVATE KEY")?;
+    PKey::private_key_from_pem(pem.as_bytes())
+        .map_err(|e| format!("can't parse {}: {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::asn1::Asn1Time;
+    use openssl::x509::extension::BasicConstraints;
+    use openssl::x509::extension::SubjectAlternativeName;
+    use openssl::x509::X509Name;
+    use pingora_core::connectors::TransportConnector;
+    use pingora_core::tls::ssl::Ssl;
+    use pingora_core::tls::ssl::SslAcceptor;
+    use pingora_core::tls::ssl::SslMethod;
+    use pingora_core::tls::ssl::SslVerifyMode;
+    use pingora_core::tls::tokio_ssl::SslStream;
+    use pingora_core::tls::x509::store::X509StoreBuilder;
+    use std::pin::Pin;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+
+    fn write(name: &str, contents: &[u8]) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("sliced-tls-{}-{}", std::process::id(), name));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    /// A certificate for `name`, signed by `issuer` or self-signed.
+    fn certificate(name: &str, issuer: Option<&(X509, PKey<Private>)>) -> (X509, PKey<Private>) {
+        let key = PKey::ec_gen("prime256v1").unwrap();
+        let mut subject = X509Name::builder().unwrap();
+        subject.append_entry_by_text("CN", name).unwrap();
+        let subject = subject.build();
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        builder.set_subject_name(&subject).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        match issuer {
+            Some((issuer_cert, issuer_key)) => {
+                let san = SubjectAlternativeName::new()
+                    .dns(name)
+                    .build(&builder.x509v3_context(Some(issuer_cert), None))
+                    .unwrap();
+                builder.append_extension(san).unwrap();
+                builder.set_issuer_name(issuer_cert.subject_name()).unwrap();
+                builder
+                    .sign(issuer_key, openssl::hash::MessageDigest::sha256())
+                    .unwrap();
+            }
+            None => {
+                let ca = BasicConstraints::new().critical().ca().build().unwrap();
+                builder.append_extension(ca).unwrap();
+                builder.set_issuer_name(&subject).unwrap();
+                builder
+                    .sign(&key, openssl::hash::MessageDigest::sha256())
+                    .unwrap();
+            }
+        }
+        (builder.build(), key)
+    }
+
+    #[test]
+    fn test_from_config() {
+        let ca = certificate("ca", None);
+        let cert = write("cert.pem", &ca.0.to_pem().unwrap());
+        let key = write("key.pem", &ca.1.private_key_to_pem_pkcs8().unwrap());
+        let config: UpstreamTlsConfig =
+            serde_json::from_str(r#"{"enabled": true, "sni": "workers.internal"}"#).unwrap();
+        assert!(UpstreamTls::from_config(&config).is_ok());
+        assert!(config.verify_cert && config.verify_hostname);
+
+        let error = |config: UpstreamTlsConfig| UpstreamTls::from_config(&config).err().unwrap();
+        assert!(error(UpstreamTlsConfig {
+            sni: None,
+            ..config.clone()
+        })
+        .contains("sni has to be set"));
+        assert!(UpstreamTls::from_config(&UpstreamTlsConfig {
+            sni: None,
+            verify_cert: false,
+            ..config.clone()
+        })
+        .is_ok());
+        assert!(error(UpstreamTlsConfig {
+            cert_file: Some(cert.clone()),
+            ..config.clone()
+        })
+        .contains("together"));
+        assert!(error(UpstreamTlsConfig {
+            cert_file: Some(key.clone()),
+            key_file: Some(cert.clone()),
+            ..config.clone()
+        })
+        .contains("no CERTIFICATE"));
+        assert!(error(UpstreamTlsConfig {
+            ca_file: Some(PathBuf::from("/nonexistent/ca.pem")),
+            ..config.clone()
+        })
+        .contains("can't read"));
+        let garbled = write(
+            "garbled.pem",
+            b"-----BEGIN CERTIFICATE-----\nMIIB\n-----END CERTIFICATE-----\n",
+        );
+        assert!(error(UpstreamTlsConfig {
+            ca_file: Some(garbled),
+            ..config.clone()
+        })
+        .contains("can't parse"));
+
+        let tls = UpstreamTls::from_config(&UpstreamTlsConfig {
+            ca_file: Some(cert.clone()),
+            cert_file: Some(cert),
+            key_file: Some(key),
+            ..config
+        })
+        .unwrap();
+        assert_eq!(tls.ca.as_ref().unwrap().len(), 1);
+        assert!(tls.client_cert_key.is_some());
+    }
+
+    #[test]
+    fn test_apply() {
+        let mut peer = HttpPeer::new("127.0.0.1:8000", false, String::new());
+        UpstreamTls::default().apply(&mut peer);
+        assert!(!peer.is_tls());
+
+        let tls = UpstreamTls::from_config(&UpstreamTlsConfig {
+            enabled: true,
+            sni: Some("workers.internal".to_string()),
+            verify_hostname: false,
+            alternative_cn: Some("worker".to_string()),
+            ..Default::default()
+        })
+        .unwrap();
+        tls.apply(&mut peer);
+        assert!(peer.is_tls());
+        assert_eq!(peer.sni, "workers.internal");
+        assert!(peer.options.verify_cert);
+        assert!(!peer.options.verify_hostname);
+        assert_eq!(peer.options.alternative_cn.as_deref(), Some("worker"));
+    }
+
+    #[tokio::test]
+    async fn test_mutual_tls() {
+        let ca = certificate("sliced test ca", None);
+        let worker = certificate("workers.internal", Some(&ca));
+        let client = certificate("sliced", Some(&ca));
+        let ca_file = write("mtls-ca.pem", &ca.0.to_pem().unwrap());
+        let cert_file = write("mtls-client.pem", &client.0.to_pem().unwrap());
+        let key_file = write(
+            "mtls-client.key",
+            &client.1.private_key_to_pem_pkcs8().unwrap(),
+        );
+
+        // A worker that only lets in clients with a certificate from the CA.
+        let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls()).unwrap();
+        acceptor.set_certificate(&worker.0).unwrap();
+        acceptor.set_private_key(&worker.1).unwrap();
+        let mut store = X509StoreBuilder::new().unwrap();
+        store.add_cert(ca.0.clone()).unwrap();
+        acceptor.set_verify_cert_store(store.build()).unwrap();
+        acceptor.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+        let acceptor = acceptor.build();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let worker = tokio::spawn(async move {
+            let mut handshakes = Vec::new();
+            for _ in 0..2 {
+                let (tcp, _) = listener.accept().await.unwrap();
+                let ssl = Ssl::new(acceptor.context()).unwrap();
+                let mut stream = SslStream::new(ssl, tcp).unwrap();
+                let accepted = Pin::new(&mut stream).accept().await;
+                handshakes.push(accepted.is_ok() && stream.ssl().peer_certificate().is_some());
+                // Hold the connection until the client is done with it.
+                let _ = stream.read(&mut [0; 1]).await;
+            }
+            handshakes
+        });
+
+        let config = UpstreamTlsConfig {
+            enabled: true,
+            sni: Some("workers.internal".to_string()),
+            ca_file: Some(ca_file),
+            cert_file: Some(cert_file),
+            key_file: Some(key_file),
+            ..Default::default()
+        };
+        let connector = TransportConnector::new(None);
+        let connect = |config: &UpstreamTlsConfig| {
+            let mut peer = HttpPeer::new(addr, false, String::new());
+            UpstreamTls::from_config(config).unwrap().apply(&mut peer);
+            peer
+        };
+        let stream = connector.new_stream(&connect(&config)).await.unwrap();
+        drop(stream);
+
+        // Without the client certificate the worker turns us away.
+        let anonymous = UpstreamTlsConfig {
+            cert_file: None,
+            key_file: None,
+            ..config
+        };
+        drop(connector.new_stream(&connect(&anonymous)).await);
+        assert_eq!(worker.await.unwrap(), vec![true, false]);
+    }
+
+    #[tokio::test]
+    async fn test_untrusted_worker() {
+        let ca = certificate("sliced test ca", None);
+        let rogue = certificate("rogue ca", None);
+        let worker = certificate("workers.internal", Some(&rogue));
+        let ca_file = write("untrusted-ca.pem", &ca.0.to_pem().unwrap());
+
+        let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls()).unwrap();
+        acceptor.set_certificate(&worker.0).unwrap();
+        acceptor.set_private_key(&worker.1).unwrap();
+        let acceptor = acceptor.build();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (tcp, _) = listener.accept().await.unwrap();
+                let ssl = Ssl::new(acceptor.context()).unwrap();
+                let mut stream = SslStream::new(ssl, tcp).unwrap();
+                let _ = Pin::new(&mut stream).accept().await;
+            }
+        });
+
+        let config = UpstreamTlsConfig {
+            enabled: true,
+            ca_file: Some(ca_file),
+            ..Default::default()
+        };
+        let connector = TransportConnector::new(None);
+        let connects = |config: UpstreamTlsConfig| {
+            let connector = &connector;
+            async move {
+                // Without an SNI pingora would connect unverified, so the
+                // config is turned away before it gets that far.
+                let Ok(tls) = UpstreamTls::from_config(&config) else {
+                    return false;
+                };
+                let mut peer = HttpPeer::new(addr, false, String::new());
+                tls.apply(&mut peer);
+                connector.new_stream(&peer).await.is_ok()
+            }
+        };
+        assert!(!connects(config.clone()).await);
+        assert!(
+            !connects(UpstreamTlsConfig {
+                sni: Some("workers.internal".to_string()),
+                ..config
+            })
+            .await
+        );
+    }
+}
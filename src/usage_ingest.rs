@@ -0,0 +1,203 @@
+use crate::api::json_response;
+use crate::api::read_body;
+use crate::api::same_token;
+use crate::api::BodyError;
+use crate::health_check::parse_usage;
+use crate::health_check::record_usage;
+use crate::health_check::HealthStatus;
+use crate::health_check::ServerStates;
+use crate::selection::SharedAssignments;
+use async_trait::async_trait;
+use http::Method;
+use http::Response;
+use http::StatusCode;
+use pingora_core::apps::http_app::ServeHttp;
+use pingora_core::protocols::http::ServerSession;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+/// The token each worker authenticates with, eg:
+///
+/// ```json
+/// {"10.0.0.1:8000": "3f9c...", "10.0.0.2:8000": "a71e..."}
+/// ```
+pub type WorkerTokens = HashMap<SocketAddr, String>;
+
+/// HTTP API workers push their usage to, instead of returning it from the
+/// health check:
+///
+/// - `POST /usage` with a [crate::health_check::Usage] body, the worker's
+///   address in `X-Worker` and its token in `Authorization: Bearer ...`.
+///
+/// Usage is checked like that from a health check and stored in the same
+/// per-server state, so the rebalancer doesn't care where it came from.
+/// Only servers that have been discovered can report.
+pub struct UsageIngest {
+    states: ServerStates,
+    assignments: SharedAssignments,
+    tokens: WorkerTokens,
+    /// See [crate::health_check::WorkerHealthCheck::usage_half_life].
+    pub usage_half_life: Duration,
+    /// See [crate::health_check::HealthCheckConfig::max_body_bytes].
+    pub max_body_bytes: usize,
+}
+
+impl UsageIngest {
+    pub fn new(states: ServerStates, assignments: SharedAssignments, tokens: WorkerTokens) -> Self {
+        Self {
+            states,
+            assignments,
+            tokens,
+            usage_half_life: Duration::ZERO,
+            max_body_bytes: 64 * 1024,
+        }
+    }
+
+    /// The worker a request is from, given its `X-Worker` and
+    /// `Authorization` headers.
+    fn authenticate(
+        &self,
+        worker: Option<&str>,
+        authorization: Option<&str>,
+    ) -> Result<(SocketAddr, HealthStatus), (StatusCode, String)> {
+        let Some(server) = worker.and_then(|w| w.parse::<SocketAddr>().ok()) else {
+            return Err((StatusCode::BAD_REQUEST, "missing X-Worker".to_string()));
+        };
+        let token = authorization.and_then(|a| a.strip_prefix("Bearer "));
+        let expected = self.tokens.get(&server);
+        match (token, expected) {
+            (Some(token), Some(expected)) if same_token(token, expected) => {}
+            _ => return Err((StatusCode::UNAUTHORIZED, "unauthorized".to_string())),
+        }
+        match self.states.get(&server) {
+            Some(health) => Ok((server, health)),
+            None => Err((StatusCode::NOT_FOUND, "unknown worker".to_string())),
+        }
+    }
+
+    fn ingest(
+        &self,
+        server: &SocketAddr,
+        health: &HealthStatus,
+        content_type: Option<&str>,
+        body: &[u8],
+        oversized: bool,
+    ) -> (StatusCode, String) {
+        match parse_usage(
+            health,
+            server,
+            Some(&self.assignments),
+            content_type,
+            body,
+            oversized,
+        ) {
+            Some(usage) => {
+                record_usage(health, usage, self.usage_half_life);
+                (StatusCode::OK, String::new())
+            }
+            None if oversized => (StatusCode::PAYLOAD_TOO_LARGE, "body too large".to_string()),
+            None => (StatusCode::BAD_REQUEST, "invalid usage".to_string()),
+        }
+    }
+
+    async fn handle(&self, session: &mut ServerSession) -> (StatusCode, String) {
+        let req = session.req_header();
+        if req.method != Method::POST || req.uri.path() != "/usage" {
+            return (StatusCode::NOT_FOUND, "not found".to_string());
+        }
+        let header = |name| req.headers.get(name).and_then(|v| v.to_str().ok());
+        let (server, health) = match self.authenticate(header("X-Worker"), header("Authorization"))
+        {
+            Ok(worker) => worker,
+            Err(response) => return response,
+        };
+        let content_type = header("Content-Type").map(str::to_string);
+
+        let (body, oversized) = match read_body(session, self.max_body_bytes).await {
+            Ok(body) => (body, false),
+            Err(BodyError::TooLarge) => (Vec::new(), true),
+            Err(e) => return (StatusCode::BAD_REQUEST, e.to_string()),
+        };
+        self.ingest(&server, &health, content_type.as_deref(), &body, oversized)
+    }
+}
+
+#[async_trait]
+impl ServeHttp for UsageIngest {
+    async fn response(&self, session: &mut ServerSession) -> Response<Vec<u8>> {
+        let (status, body) = self.handle(session).await;
+        json_response(status, body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::slice_assignments::SliceAssignments;
+
+    #[test]
+    fn test_ingest() {
+        let server: SocketAddr = "127.0.0.1:8000".parse().unwrap();
+        let other: SocketAddr = "127.0.0.1:8001".parse().unwrap();
+        let states = ServerStates::default();
+        let health = states.status(server);
+        let assignments = SliceAssignments::new(vec![server, other]);
+        let shared = SharedAssignments::default();
+        shared.store(&assignments);
+        let tokens = HashMap::from([
+            (server, "secret".to_string()),
+            ("127.0.0.1:9000".parse().unwrap(), "other".to_string()),
+        ]);
+        let ingest = UsageIngest::new(states, shared, tokens);
+
+        let status = |worker, authorization| {
+            ingest
+                .authenticate(worker, authorization)
+                .map_err(|(status, _)| status)
+                .map(|_| StatusCode::OK)
+        };
+        let worker = Some("127.0.0.1:8000");
+        assert_eq!(
+            status(None, Some("Bearer secret")),
+            Err(StatusCode::BAD_REQUEST)
+        );
+        assert_eq!(status(worker, None), Err(StatusCode::UNAUTHORIZED));
+        assert_eq!(
+            status(worker, Some("secret")),
+            Err(StatusCode::UNAUTHORIZED)
+        );
+        assert_eq!(
+            status(worker, Some("Bearer other")),
+            Err(StatusCode::UNAUTHORIZED)
+        );
+        assert_eq!(
+            status(Some("127.0.0.1:9000"), Some("Bearer other")),
+            Err(StatusCode::NOT_FOUND)
+        );
+        assert_eq!(status(worker, Some("Bearer secret")), Ok(StatusCode::OK));
+
+        assert_eq!(
+            ingest.ingest(&server, &health, None, b"", true).0,
+            StatusCode::PAYLOAD_TOO_LARGE
+        );
+        assert_eq!(
+            ingest
+                .ingest(&server, &health, Some("application/json"), b"nope", false)
+                .0,
+            StatusCode::BAD_REQUEST
+        );
+        let owned = (0..).find(|&s| assignments.owns(&server, s)).unwrap();
+        let body = format!(r#"{{"slices": {{"{}": {{"load": 4}}}}}}"#, owned);
+        assert_eq!(
+            ingest
+                .ingest(&server, &health, None, body.as_bytes(), false)
+                .0,
+            StatusCode::OK
+        );
+        let state = health.inner.read().unwrap();
+        assert_eq!(state.usage.as_ref().unwrap().slices[&owned].load, 4);
+        assert_eq!(state.reports.oversized, 1);
+        assert_eq!(state.reports.malformed, 1);
+    }
+}